@@ -0,0 +1,132 @@
+// Compare the throughput of the work-stealing `ThreadPool` with the design it replaced, where every
+// worker takes its jobs from one channel behind a mutex. Each run submits a large number of tiny
+// jobs from several threads, like a storm of cheap searches arriving on several connections, and
+// times how long it takes for all of them to finish.
+//
+// The deques can only pay off when workers run in parallel. On a single CPU both designs manage
+// about the same throughput, and the difference between them is within the noise of a run.
+//
+// Run with `cargo bench --bench pool`.
+
+use ngram::pool::{PoolConfig, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const JOBS: usize = 500_000;
+const SUBMITTERS: usize = 4;
+const RUNS: usize = 5;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// The previous thread pool: a channel whose receiver is shared by all the workers behind a mutex,
+// so every job taken from the queue goes through the same lock.
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let _ = self.sender.as_ref().unwrap().send(Box::new(f));
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Submit `JOBS` jobs with `execute`, split between `SUBMITTERS` threads, and wait for all of them
+// to run, returning how long it took. The pool is dropped to wait, since dropping either pool runs
+// every queued job first.
+fn run<P, E>(pool: P, execute: E) -> Duration
+where
+    P: Sync,
+    E: Fn(&P, Box<dyn FnOnce() + Send>) + Sync,
+{
+    let counter = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..SUBMITTERS {
+            scope.spawn(|| {
+                for _ in 0..JOBS / SUBMITTERS {
+                    let counter = Arc::clone(&counter);
+                    execute(
+                        &pool,
+                        Box::new(move || {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        }),
+                    );
+                }
+            });
+        }
+    });
+    drop(pool);
+    let elapsed = start.elapsed();
+    assert_eq!(counter.load(Ordering::Relaxed), JOBS);
+    elapsed
+}
+
+// The median of several timings, in jobs per second.
+fn throughput(mut timings: Vec<Duration>) -> f64 {
+    timings.sort();
+    JOBS as f64 / timings[timings.len() / 2].as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>16} {:>16} {:>8}",
+        "workers", "channel jobs/s", "stealing jobs/s", "speedup"
+    );
+    for workers in [1, 4, 16] {
+        let channel: Vec<Duration> = (0..RUNS)
+            .map(|_| run(ChannelPool::new(workers), |pool, job| pool.execute(job)))
+            .collect();
+        let stealing: Vec<Duration> = (0..RUNS)
+            .map(|_| {
+                let pool = ThreadPool::with_config(PoolConfig {
+                    min_workers: workers,
+                    max_workers: workers,
+                    queue_capacity: JOBS,
+                    ..Default::default()
+                });
+                run(pool, |pool, job| pool.execute(job))
+            })
+            .collect();
+        let (channel, stealing) = (throughput(channel), throughput(stealing));
+        println!(
+            "{:>8} {:>16.0} {:>16.0} {:>7.2}x",
+            workers,
+            channel,
+            stealing,
+            stealing / channel
+        );
+    }
+}
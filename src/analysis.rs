@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Text analysis turns a document or a query into the sequence of terms that are stored in (or
+// looked up in) the reverse index. The same tokenizer must be used for both, otherwise a query can
+// never match the terms it was indexed under, so a `Database` owns a single tokenizer and applies
+// it everywhere.
+
+/// A single term produced by a `Tokenizer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// The normalized term, as stored in the reverse index
+    pub text: String,
+    /// The byte offset in the original text where the term starts
+    pub start: usize,
+    /// The byte offset in the original text just past the end of the term
+    pub end: usize,
+}
+
+/// Splits text into the terms that are indexed and searched for
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    // Split `text` into terms, in the order they appear.
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+
+    // Whether every term is lowercased, in which case patterns matched against the terms (which
+    // can't be tokenized themselves) are lowercased too.
+    fn lowercases(&self) -> bool {
+        false
+    }
+}
+
+/// What the standard tokenizer does with terms that consist only of digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Numerals {
+    /// Index numbers like any other term
+    Keep,
+    /// Leave numbers out of the index
+    Drop,
+}
+
+/// The built-in tokenizer. Each step can be switched off, so the plain whitespace splitting used
+/// before analysis was configurable is `lowercase` and `strip_punctuation` both set to false.
+#[derive(Debug, Clone)]
+pub struct StandardTokenizer {
+    /// Lowercase terms using Unicode case mapping, so "Macbeth" and "macbeth" are the same term
+    pub lowercase: bool,
+    /// Split on punctuation as well as whitespace, so "Macbeth," and "Macbeth." are both
+    /// "Macbeth". An apostrophe between two letters is kept, so "don't" stays a single term.
+    pub strip_punctuation: bool,
+    /// Whether numbers are indexed
+    pub numerals: Numerals,
+    /// Terms to leave out of the index. These are compared after lowercasing and before stemming.
+    pub stopwords: HashSet<String>,
+    /// Reduce plural terms to their singular form with `stem`
+    pub stem: bool,
+}
+
+impl Default for StandardTokenizer {
+    fn default() -> Self {
+        StandardTokenizer {
+            lowercase: true,
+            strip_punctuation: true,
+            numerals: Numerals::Keep,
+            stopwords: HashSet::new(),
+            stem: false,
+        }
+    }
+}
+
+impl Tokenizer for StandardTokenizer {
+    // Split the text into raw words, then lowercase, filter and stem each one in turn.
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let words = if self.strip_punctuation {
+            split_words(text)
+        } else {
+            split_whitespace(text)
+        };
+
+        words
+            .into_iter()
+            .filter_map(|(start, end)| {
+                let word = &text[start..end];
+                if self.numerals == Numerals::Drop && word.chars().all(char::is_numeric) {
+                    return None;
+                }
+                let word = if self.lowercase {
+                    word.to_lowercase()
+                } else {
+                    word.to_string()
+                };
+                if self.stopwords.contains(&word) {
+                    return None;
+                }
+                let text = if self.stem { stem(&word) } else { word };
+                Some(Token { text, start, end })
+            })
+            .collect()
+    }
+
+    fn lowercases(&self) -> bool {
+        self.lowercase
+    }
+}
+
+// Read a stopword list with one word per line, such as `data/words.txt`. Blank lines are ignored
+// and words are lowercased, to match the default tokenizer.
+pub fn load_stopwords(path: &Path) -> io::Result<HashSet<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+// Reduce an English plural to its singular form, following Harman's "S" stemmer: "-ies" becomes
+// "-y", "-es" becomes "-e" and a final "-s" is dropped, except after another "s" or a "u" (so
+// "glass" and "thus" are unchanged). This is deliberately light, so it rarely conflates unrelated
+// words.
+pub fn stem(word: &str) -> String {
+    if word.chars().count() <= 3 {
+        return word.to_string();
+    }
+    if let Some(stripped) = word.strip_suffix("ies") {
+        if !stripped.ends_with(['a', 'e']) {
+            return format!("{}y", stripped);
+        }
+    }
+    if let Some(stripped) = word.strip_suffix("es") {
+        if !stripped.ends_with(['a', 'e', 'o']) {
+            return format!("{}e", stripped);
+        }
+    }
+    if let Some(stripped) = word.strip_suffix('s') {
+        if !stripped.ends_with(['s', 'u']) {
+            return stripped.to_string();
+        }
+    }
+    word.to_string()
+}
+
+// The byte spans of the whitespace-separated words of `text`.
+fn split_whitespace(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+// The byte spans of the maximal runs of alphanumeric characters in `text`, where a single
+// apostrophe between two alphanumeric characters is part of the run.
+fn split_words(text: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (n, &(i, c)) in chars.iter().enumerate() {
+        let in_word = c.is_alphanumeric()
+            || (is_apostrophe(c)
+                && start.is_some()
+                && chars.get(n + 1).is_some_and(|(_, c)| c.is_alphanumeric()));
+        match (in_word, start) {
+            (false, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            (true, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+fn is_apostrophe(c: char) -> bool {
+    c == '\'' || c == '\u{2019}'
+}
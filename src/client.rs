@@ -1,12 +1,18 @@
+use crate::database::TextRange;
+use crate::dictionary::TermPattern;
 use crate::message::*;
+use std::collections::HashMap;
 use std::default::Default;
-// use std::io::Read;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Mutex, MutexGuard};
 
 /// A client for interacting with the server at address `address`
 pub struct Client {
     address: SocketAddr,
+    /// The connection to the server, which is opened by the first request and then reused
+    connection: Mutex<Option<Connection>>,
 }
 impl Default for Client {
     fn default() -> Self {
@@ -14,56 +20,488 @@ impl Default for Client {
     }
 }
 
+/// An error returned by a `Client` request
+#[derive(Debug)]
+pub enum ClientError {
+    /// The client could not connect to the server
+    Connect(io::Error),
+    /// Sending the request or receiving the response failed, or a document to publish could not
+    /// be read
+    Io(io::Error),
+    /// The server sent a response that could not be decoded
+    Decode(DecodeError),
+    /// The server could not carry out the request
+    Server(ErrorResponse),
+    /// The server doesn't speak a compatible version of the protocol
+    Incompatible(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connect(err) => write!(f, "failed to connect to server: {}", err),
+            ClientError::Io(err) => write!(f, "I/O error: {}", err),
+            ClientError::Decode(err) => write!(f, "invalid response from server: {}", err),
+            ClientError::Server(err) => write!(f, "server error: {}", err),
+            ClientError::Incompatible(reason) => write!(f, "incompatible server: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<DecodeError> for ClientError {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::Truncated => ClientError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection",
+            )),
+            DecodeError::Io(err) => ClientError::Io(err),
+            err => ClientError::Decode(err),
+        }
+    }
+}
+
+/// An open connection to the server
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// The id to give the next request sent on this connection
+    next_id: u64,
+    /// The capabilities that both the client and the server support
+    capabilities: u32,
+    /// Whether any bytes have been written since the flag was last cleared, meaning the server
+    /// may have received a request even if sending it failed
+    written: bool,
+}
+
+impl Connection {
+    // Connect to the server and exchange handshakes with it. Fails if the server doesn't speak a
+    // protocol version that the client does.
+    fn open(address: SocketAddr) -> Result<Connection, ClientError> {
+        let mut stream = TcpStream::connect(address).map_err(ClientError::Connect)?;
+        stream.set_nodelay(true).map_err(ClientError::Connect)?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(ClientError::Connect)?);
+
+        let ours = Hello::default();
+        stream
+            .write_all(&ours.to_bytes())
+            .map_err(ClientError::Io)?;
+        let theirs = match Hello::from_bytes(&mut reader) {
+            Ok(hello) => hello,
+            Err(DecodeError::BadMagic) => {
+                return Err(ClientError::Incompatible(
+                    "server does not speak protocol version 2 or newer".to_string(),
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (_, capabilities) = ours.negotiate(&theirs).ok_or_else(|| {
+            ClientError::Incompatible(format!(
+                "server speaks protocol versions {}-{}, but the client speaks {}-{}",
+                theirs.min_version, theirs.max_version, ours.min_version, ours.max_version
+            ))
+        })?;
+
+        Ok(Connection {
+            reader,
+            writer: stream,
+            next_id: 0,
+            capabilities,
+            written: false,
+        })
+    }
+
+    // Whether the server has already closed the connection, as it does with idle connections.
+    // This only looks for a close that has arrived, without waiting for one.
+    fn is_closed(&self) -> bool {
+        let stream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match stream.peek(&mut [0; 1]) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+        };
+        closed || stream.set_nonblocking(false).is_err()
+    }
+
+    // Write all of `bytes` to the server, noting whether any of them were written before a
+    // failure.
+    fn write(&mut self, mut bytes: &[u8]) -> Result<(), ClientError> {
+        while !bytes.is_empty() {
+            match self.writer.write(bytes) {
+                Ok(0) => return Err(ClientError::Io(io::ErrorKind::WriteZero.into())),
+                Ok(count) => {
+                    self.written = true;
+                    bytes = &bytes[count..];
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(ClientError::Io(err)),
+            }
+        }
+        Ok(())
+    }
+
+    // Send every request in a single write, then read response frames until there is one for each
+    // request. The server may answer the requests in any order, so responses are matched to
+    // requests by their ids. Returns the responses in the same order as the requests.
+    fn exchange(&mut self, requests: &[Request]) -> Result<Vec<Response>, ClientError> {
+        let first_id = self.next_id;
+        self.next_id += requests.len() as u64;
+        let mut bytes = Vec::new();
+        for (id, request) in (first_id..).zip(requests) {
+            bytes.extend(request.to_frame(id).map_err(ClientError::Io)?);
+        }
+        self.write(&bytes)?;
+
+        let mut responses = HashMap::new();
+        while responses.len() < requests.len() {
+            let (id, response) = Response::from_frame(&mut self.reader)?;
+            // A streamed retrieve is answered with several chunks, and only the last one is kept
+            if let Response::Chunk { last: false, .. } = response {
+                continue;
+            }
+            if (first_id..self.next_id).contains(&id) {
+                responses.insert(id, response);
+            }
+        }
+        Ok((first_id..self.next_id)
+            .map(|id| responses.remove(&id).unwrap())
+            .collect())
+    }
+
+    // Send a request and read the first response frame to it, leaving any further frames to be
+    // read by the caller. Returns the request's id along with the response.
+    fn start(&mut self, request: &Request) -> Result<(u64, Response), ClientError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write(&request.to_frame(id).map_err(ClientError::Io)?)?;
+        loop {
+            let (response_id, response) = Response::from_frame(&mut self.reader)?;
+            if response_id == id {
+                return Ok((id, response));
+            }
+        }
+    }
+}
+
+/// A document being streamed from the server by `Client::retrieve_stream`, which can be read
+/// with `io::Read`. The client's connection is reserved for the stream until it is dropped.
+pub struct DocumentReader<'a> {
+    connection: MutexGuard<'a, Option<Connection>>,
+    /// The id of the `RetrieveStream` request
+    id: u64,
+    /// The most recent chunk received from the server
+    chunk: Vec<u8>,
+    /// How much of `chunk` has been read
+    position: usize,
+    /// Whether the last chunk has been received
+    finished: bool,
+}
+
+impl DocumentReader<'_> {
+    // Replace the current chunk with the next one from the server.
+    fn next_chunk(&mut self) -> Result<(), ClientError> {
+        let connection = self.connection.as_mut().unwrap();
+        loop {
+            let (id, response) = Response::from_frame(&mut connection.reader)?;
+            if id != self.id {
+                continue;
+            }
+            return match response {
+                Response::Chunk { data, last } => {
+                    self.chunk = data;
+                    self.position = 0;
+                    self.finished = last;
+                    Ok(())
+                }
+                Response::Error(err) => Err(ClientError::Server(err)),
+                _ => Err(ClientError::Decode(DecodeError::Malformed)),
+            };
+        }
+    }
+}
+
+impl Read for DocumentReader<'_> {
+    // Copy as much of the current chunk as fits into `buf`, first waiting for the next chunk if
+    // the current one has been used up. A failure ends the stream and closes the connection, since
+    // the rest of the stream can no longer be told apart from later responses.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            if let Err(err) = self.next_chunk() {
+                self.finished = true;
+                *self.connection = None;
+                return Err(match err {
+                    ClientError::Io(err) => err,
+                    err => io::Error::other(err),
+                });
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl Drop for DocumentReader<'_> {
+    // A stream that is dropped before its last chunk leaves chunks on the connection that would
+    // be mistaken for later responses, so the connection is closed instead.
+    fn drop(&mut self) {
+        if !self.finished {
+            *self.connection = None;
+        }
+    }
+}
+
 impl Client {
     // Create a client that will connect to the server at `address` and `port`. You can create a
     // SocketAddr from an IpAddr and a port with `SocketAddr::new(addr, port)`.
-    // You can create an IpAddr from a string with `address.parse().unwrap()`.
+    // You can create an IpAddr from a string with `address.parse().unwrap()`. No connection is
+    // made until the first request is sent.
     pub fn new(address: &str, port: u16) -> Self {
         Client {
             address: SocketAddr::new(address.parse().unwrap(), port),
+            connection: Mutex::new(None),
         }
     }
 
-    // This function is optional, but you may find it useful.
-    // Convert the request to bytes, send it to the server, read the response to bytes, and convert
-    // the response to a Response. If the response is invalid, return `None`.
+    // Send all of `requests` to the server without waiting for any responses in between, and
+    // return their responses in the same order. Requests that fail are answered with
+    // `Response::Error` rather than failing the whole pipeline.
     //
-    // You can connect to the server with `std::net::TcpStream::connect(address)` function.
-    // You can write to the stream with `stream.write_all(&bytes)`.
-    // You can read from the stream by calling your `Response::from_bytes` function, since
-    // `TcpStream` implements `Read`.
-    fn send(&self, request: &Request) -> Option<Response> {
-        let mut stream = std::net::TcpStream::connect(self.address).unwrap();
-        let bytes = request.to_bytes();
-        let _result = stream.write_all(&bytes).unwrap();
+    // Requests are sent over the client's persistent connection, which is opened if necessary. The
+    // server closes connections that are idle for too long, so a connection the server has closed
+    // is reopened before it is used. If a reused connection fails anyway, the requests are sent
+    // once more on a new connection, but only if none of them could have reached the server, or
+    // they are all idempotent, so that a publish or delete is never carried out twice.
+    pub fn pipeline(&self, requests: &[Request]) -> Result<Vec<Response>, ClientError> {
+        let idempotent = requests.iter().all(Request::is_idempotent);
+        self.with_connection(idempotent, |connection| connection.exchange(requests))
+            .map(|(_, responses)| responses)
+    }
 
-        let ans = Response::from_bytes(stream);
-        return ans;
+    // Run `f` on the client's connection, opening it if necessary, and return the result along
+    // with the locked connection. If a reused connection fails, it is reopened and `f` runs once
+    // more, as described for `pipeline`; `idempotent` says whether `f` may be repeated after it
+    // has written to the connection.
+    fn with_connection<F, R>(
+        &self,
+        idempotent: bool,
+        mut f: F,
+    ) -> Result<(MutexGuard<'_, Option<Connection>>, R), ClientError>
+    where
+        F: FnMut(&mut Connection) -> Result<R, ClientError>,
+    {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.as_ref().is_some_and(Connection::is_closed) {
+            *connection = None;
+        }
+        if let Some(open) = connection.as_mut() {
+            open.written = false;
+            match f(open) {
+                Ok(result) => return Ok((connection, result)),
+                // A request too large to frame fails before anything is written, but it would
+                // fail again on a new connection
+                Err(ClientError::Io(err))
+                    if err.kind() != io::ErrorKind::InvalidInput
+                        && (idempotent || !open.written) => {}
+                Err(err) => {
+                    *connection = None;
+                    return Err(err);
+                }
+            }
+        }
+        *connection = None;
+
+        let mut fresh = Connection::open(self.address)?;
+        let result = f(&mut fresh)?;
+        *connection = Some(fresh);
+        Ok((connection, result))
+    }
+
+    // The capabilities that both the client and the server support, as a bitmap of the `CAP_`
+    // constants in `message`. This connects to the server if the client isn't connected yet.
+    pub fn capabilities(&self) -> Result<u32, ClientError> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(open) = connection.as_ref() {
+            return Ok(open.capabilities);
+        }
+        let fresh = Connection::open(self.address)?;
+        let capabilities = fresh.capabilities;
+        *connection = Some(fresh);
+        Ok(capabilities)
+    }
+
+    // Send a single request to the server and return its response. An error response from the
+    // server is returned as `ClientError::Server`.
+    fn send(&self, request: &Request) -> Result<Response, ClientError> {
+        match self.pipeline(std::slice::from_ref(request))?.remove(0) {
+            Response::Error(err) => Err(ClientError::Server(err)),
+            response => Ok(response),
+        }
     }
 
     // Read the file at `path` and send a `Publish` request to the server with its contents.
     // Return the response from the server.
     //
     // You can read the contents of a file with `let s = std::fs::read_to_string(path)`.
-    pub fn publish_from_path(&self, path: &str) -> Option<Response> {
-        let s = std::fs::read_to_string(path).unwrap();
+    pub fn publish_from_path(&self, path: &str) -> Result<Response, ClientError> {
+        let s = std::fs::read_to_string(path).map_err(ClientError::Io)?;
         let request = Request::Publish { doc: s };
-        return self.send(&request);
+        self.send(&request)
     }
 
-    // Send a `Search` request to the server with the given `word`. Return the response from the
-    // server.
-    pub fn search(&self, word: &str) -> Option<Response> {
+    // Send a `Search` request to the server with the given `word`, which may be a phrase of
+    // several whitespace-separated words. Return the response from the server.
+    pub fn search(&self, word: &str) -> Result<Response, ClientError> {
         let request = Request::Search {
             word: word.to_string(),
         };
-        return self.send(&request);
+        self.send(&request)
     }
     // TODO:
     // Send a `Retrieve` request to the server with the given `id`. Return the response from the
     // server.
-    pub fn retrieve(&self, id: usize) -> Option<Response> {
-        let request = Request::Retrieve { id: id };
-        return self.send(&request);
+    pub fn retrieve(&self, id: usize) -> Result<Response, ClientError> {
+        let request = Request::Retrieve { id };
+        self.send(&request)
+    }
+
+    // Send a `Frequency` request to the server with the given `word`, which may be a phrase of
+    // several whitespace-separated words. Return the response from the server.
+    pub fn frequency(&self, word: &str) -> Result<Response, ClientError> {
+        let request = Request::Frequency {
+            word: word.to_string(),
+        };
+        self.send(&request)
+    }
+
+    // Send a `Metadata` request to the server with the given `id`. Return the response from the
+    // server.
+    pub fn metadata(&self, id: usize) -> Result<Response, ClientError> {
+        let request = Request::Metadata { id };
+        self.send(&request)
+    }
+
+    // Send a `Timeline` request to the server with the given `word`, which may be a phrase of
+    // several whitespace-separated words. Return the response from the server.
+    pub fn timeline(&self, word: &str) -> Result<Response, ClientError> {
+        let request = Request::Timeline {
+            word: word.to_string(),
+        };
+        self.send(&request)
+    }
+
+    // Send a `Delete` request to the server with the given `id`. Return the response from the
+    // server.
+    pub fn delete(&self, id: usize) -> Result<Response, ClientError> {
+        let request = Request::Delete { id };
+        self.send(&request)
+    }
+
+    // Send a `RankedSearch` request to the server for the `limit` documents most relevant to the
+    // words in `word`. Return the response from the server.
+    pub fn ranked_search(&self, word: &str, limit: usize) -> Result<Response, ClientError> {
+        let request = Request::RankedSearch {
+            word: word.to_string(),
+            limit,
+        };
+        self.send(&request)
+    }
+
+    // Send a `Query` request to the server with the given boolean query, such as
+    // `(witch OR witches) AND NOT hamlet`. Return the response from the server.
+    pub fn query(&self, query: &str) -> Result<Response, ClientError> {
+        let request = Request::Query {
+            query: query.to_string(),
+        };
+        self.send(&request)
+    }
+
+    // Send a `Concordance` request to the server for up to `snippets` occurrences of `word` in
+    // each matching document, with `context` words on either side. Return the response from the
+    // server.
+    pub fn concordance(
+        &self,
+        word: &str,
+        context: usize,
+        snippets: usize,
+    ) -> Result<Response, ClientError> {
+        let request = Request::Concordance {
+            word: word.to_string(),
+            context,
+            snippets,
+        };
+        self.send(&request)
+    }
+
+    // Send a `PatternSearch` request to the server for the documents containing any term that
+    // matches `pattern`. Return the response from the server.
+    pub fn pattern_search(&self, pattern: TermPattern) -> Result<Response, ClientError> {
+        let request = Request::PatternSearch { pattern };
+        self.send(&request)
+    }
+
+    // Send a `SearchPage` request to the server for up to `limit` of the documents containing
+    // `word`. Pass `None` as the cursor for the first page, and then the `next` cursor of each
+    // page to get the one after it. Return the response from the server.
+    pub fn search_page(
+        &self,
+        word: &str,
+        limit: usize,
+        cursor: Option<usize>,
+    ) -> Result<Response, ClientError> {
+        let request = Request::SearchPage {
+            word: word.to_string(),
+            limit,
+            cursor,
+        };
+        self.send(&request)
+    }
+
+    // Send a `Stats` request to the server for how busy it is. Return the response from the
+    // server.
+    pub fn stats(&self) -> Result<Response, ClientError> {
+        self.send(&Request::Stats)
+    }
+
+    // Stream the part `range` of the document with the given `id` from the server. The document
+    // arrives in chunks, which the returned reader hands out as they are read, so the whole
+    // document never has to be held in memory at once. Fails straight away if the document
+    // doesn't exist.
+    pub fn retrieve_stream(
+        &self,
+        id: usize,
+        range: TextRange,
+    ) -> Result<DocumentReader<'_>, ClientError> {
+        let request = Request::RetrieveStream { id, range };
+        let (connection, (id, response)) =
+            self.with_connection(true, |connection| connection.start(&request))?;
+        let mut reader = DocumentReader {
+            connection,
+            id,
+            chunk: Vec::new(),
+            position: 0,
+            finished: true,
+        };
+        match response {
+            Response::Chunk { data, last } => {
+                reader.chunk = data;
+                reader.finished = last;
+                Ok(reader)
+            }
+            Response::Error(err) => Err(ClientError::Server(err)),
+            _ => {
+                *reader.connection = None;
+                Err(ClientError::Decode(DecodeError::Malformed))
+            }
+        }
     }
 }
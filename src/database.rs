@@ -1,63 +1,977 @@
+use crate::analysis::{StandardTokenizer, Tokenizer};
+use crate::dictionary::{PatternError, TermDictionary, TermPattern};
 use crate::multimap::ConcurrentMultiMap;
-use std::sync::Mutex;
+use crate::query::Query;
+use crate::storage::{Record, Storage};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 
 // The archive struct contains two data structures: a ConcurrentMultiMap for storing the
-// reverse index that maps words to the documents they appear in, and a Mutex<Vec<String>> for
-// storing the documents themselves. Since the documents themselves aren't accessed as often, it's
-// ok to keep them behind a single mutex.
+// reverse index that maps n-grams to the documents they appear in (along with the positions they
+// appear at), and a Mutex<Vec<Option<Document>>> for storing the documents themselves. Since the
+// documents themselves aren't accessed as often, it's ok to keep them behind a single mutex.
+//
+// Ids are indexes into the blob store, so deleting a document can't remove its slot. Instead, the
+// slot is emptied and the id is added to a set of tombstones, which is used to filter the document
+// out of search results straight away. Compaction later removes the document's entries from the
+// reverse index, after which its tombstone is no longer needed.
+//
+// A database can optionally be backed by a `Storage`, which records every change in a write-ahead
+// log before it is applied. The storage is only locked while the blob store lock is held, so
+// changes are logged in the same order as ids are assigned.
 
 /// A document database that allows clients to publish documents and
-/// search for documents containing specific words.
+/// search for documents containing specific words or phrases.
 pub struct Database {
-    /// A map from words to the set of documents that contain them
-    reverse_index: ConcurrentMultiMap<String, usize>,
+    /// A map from n-grams to the documents that contain them
+    reverse_index: ConcurrentMultiMap<String, Posting>,
+    /// The sorted set of single terms in the reverse index
+    dictionary: TermDictionary,
     /// A store of all documents in the database
-    blob_store: Mutex<Vec<String>>,
+    blob_store: Mutex<Vec<Option<Document>>>,
+    /// The ids of deleted documents that still have entries in the reverse index
+    tombstones: RwLock<HashSet<usize>>,
+    /// The longest n-gram that is stored in the reverse index
+    max_ngram: usize,
+    /// Splits documents and queries into the terms stored in the reverse index
+    tokenizer: Arc<dyn Tokenizer>,
+    /// The BM25 term frequency saturation parameter
+    k1: f64,
+    /// The BM25 length normalization parameter
+    b: f64,
+    /// The most terms that a pattern search may expand to
+    max_expansions: usize,
+    /// The most edits between a word and the terms suggested in its place
+    suggestion_distance: usize,
+    /// The on-disk log and snapshots, if the database is persistent
+    storage: Option<Mutex<Storage>>,
+}
+
+/// An error returned by a `Database` operation
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// Reading or writing the database's data directory failed
+    Io(io::Error),
+    /// No document with the given id has been published
+    NotFound(usize),
+    /// The document with the given id has been deleted
+    Deleted(usize),
+    /// A term pattern could not be searched for
+    Pattern(PatternError),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Io(err) => write!(f, "storage error: {}", err),
+            DatabaseError::NotFound(id) => write!(f, "document {} does not exist", id),
+            DatabaseError::Deleted(id) => write!(f, "document {} has been deleted", id),
+            DatabaseError::Pattern(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<io::Error> for DatabaseError {
+    fn from(err: io::Error) -> Self {
+        DatabaseError::Io(err)
+    }
+}
+
+impl From<PatternError> for DatabaseError {
+    fn from(err: PatternError) -> Self {
+        DatabaseError::Pattern(err)
+    }
+}
+
+/// Options controlling how a `Database` indexes its documents
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    /// The longest n-gram to index. Every n-gram of length `1..=max_ngram` is stored in the
+    /// reverse index; longer phrases are answered by intersecting the positions of their n-grams.
+    pub max_ngram: usize,
+    /// How documents and queries are split into terms. Changing the tokenizer of a persistent
+    /// database is safe, since the reverse index is rebuilt from the documents when it is opened.
+    pub tokenizer: Arc<dyn Tokenizer>,
+    /// How quickly repeated occurrences of a term stop adding to a document's BM25 score. Zero
+    /// ignores term frequency entirely.
+    pub k1: f64,
+    /// How much a document's length is normalized away in its BM25 score, from 0 (not at all) to
+    /// 1 (fully)
+    pub b: f64,
+    /// The most terms that a prefix, wildcard or regular expression search may match. Broader
+    /// patterns are refused rather than answered partially.
+    pub max_expansions: usize,
+    /// The most single-character edits between a word that matched nothing and the terms that
+    /// are suggested in its place
+    pub suggestion_distance: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            max_ngram: 1,
+            tokenizer: Arc::new(StandardTokenizer::default()),
+            k1: 1.2,
+            b: 0.75,
+            max_expansions: 4096,
+            suggestion_distance: 2,
+        }
+    }
+}
+
+/// A document that matched a ranked search, along with how relevant it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredDocument {
+    /// The id of the document
+    pub id: usize,
+    /// The document's BM25 score; higher is more relevant
+    pub score: f64,
+}
+
+/// The most spelling suggestions returned for a word
+const SUGGESTIONS: usize = 5;
+
+/// A document containing a word or phrase, along with passages showing it in context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// The id of the document
+    pub id: usize,
+    /// The number of times the word or phrase occurs in the document, which may be more than the
+    /// number of snippets
+    pub count: usize,
+    /// The first occurrences of the word or phrase, in the order they appear
+    pub snippets: Vec<Snippet>,
+}
+
+/// A single occurrence of a word or phrase, with the text around it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    /// The byte offset in the document where the occurrence starts
+    pub start: usize,
+    /// The byte offset in the document just past the end of the occurrence
+    pub end: usize,
+    /// The text before the occurrence, starting at the first term of the context
+    pub before: String,
+    /// The occurrence itself, as it is written in the document
+    pub matched: String,
+    /// The text after the occurrence, ending at the last term of the context
+    pub after: String,
+}
+
+/// One page of the documents matching a search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchPage {
+    /// The ids of the documents on this page, in ascending order
+    pub ids: Vec<usize>,
+    /// The number of documents matching the search across all pages
+    pub total: usize,
+    /// The cursor that fetches the next page, or `None` if this is the last page
+    pub next: Option<usize>,
+}
+
+/// How often a word or phrase occurs in a single document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frequency {
+    /// The id of the document
+    pub id: usize,
+    /// The number of times the word or phrase occurs in the document
+    pub count: usize,
+    /// `count` divided by the total number of terms in the document
+    pub relative: f64,
+}
+
+/// How often a word or phrase occurs across all documents published in a single year
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelinePoint {
+    /// The publication year
+    pub year: i32,
+    /// The number of times the word or phrase occurs in documents from `year`
+    pub count: usize,
+    /// The total number of words in documents from `year`
+    pub word_count: usize,
+    /// `count` divided by `word_count`
+    pub relative: f64,
+}
+
+/// A part of a document to retrieve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextRange {
+    /// The whole document
+    All,
+    /// The bytes from `start` up to but not including `end`
+    Bytes { start: usize, end: usize },
+    /// The lines from `first` to `last` inclusive, counting from 1, along with their line breaks
+    Lines { first: usize, last: usize },
+}
+
+impl TextRange {
+    // Find the byte range of `text` covered by this range, clamped to the end of the text. A range
+    // that starts past the end of the text is empty.
+    pub fn resolve(&self, text: &str) -> (usize, usize) {
+        match *self {
+            TextRange::All => (0, text.len()),
+            TextRange::Bytes { start, end } => {
+                let end = end.min(text.len());
+                (start.min(end), end)
+            }
+            TextRange::Lines { first, last } => {
+                // The offset just past the line break that ends line `n`, or the end of the text
+                let line_end = |n: usize| {
+                    if n == 0 {
+                        return 0;
+                    }
+                    text.match_indices('\n')
+                        .nth(n - 1)
+                        .map_or(text.len(), |(i, _)| i + 1)
+                };
+                let start = line_end(first.saturating_sub(1));
+                (start, line_end(last).max(start))
+            }
+        }
+    }
+}
+
+/// Descriptive information about a published document
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub year: Option<i32>,
+}
+
+impl Metadata {
+    // Parse the metadata from the header line at the start of a document. Headers follow the
+    // convention of the files in `data/`, e.g. `[The Tragedie of Macbeth by William Shakespeare
+    // 1603]`: a bracketed title, optionally followed by `by` and the author, optionally followed
+    // by the year of publication. Documents without a header have empty metadata.
+    pub fn from_header(doc: &str) -> Metadata {
+        let header = match split_header(doc) {
+            Some((header, _)) => header,
+            None => return Metadata::default(),
+        };
+
+        let (rest, year) = match header.rsplit_once(' ') {
+            Some((rest, last)) if last.chars().all(|c| c.is_ascii_digit()) => {
+                (rest.trim_end(), last.parse().ok())
+            }
+            _ => (header, None),
+        };
+        let (title, author) = match rest.rsplit_once(" by ") {
+            Some((title, author)) => (title.trim_end_matches(',').trim(), Some(author.trim())),
+            None => (rest, None),
+        };
+
+        Metadata {
+            title: Some(title.to_string()).filter(|title| !title.is_empty()),
+            author: author
+                .filter(|author| !author.is_empty())
+                .map(str::to_string),
+            year,
+        }
+    }
+}
+
+// Find the bracketed header line at the start of a document. Returns the header without its
+// brackets, along with the byte offset at which the body of the document starts, just after the
+// header line.
+fn split_header(doc: &str) -> Option<(&str, usize)> {
+    let body = doc.find('\n').map_or(doc.len(), |newline| newline + 1);
+    let header = doc[..body]
+        .trim()
+        .strip_prefix('[')
+        .and_then(|line| line.strip_suffix(']'))?;
+    Some((header.trim(), body))
+}
+
+/// An entry in the reverse index recording where an n-gram occurs in document `doc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Posting {
+    doc: usize,
+    /// The term positions at which the n-gram starts, in ascending order
+    positions: Vec<usize>,
+}
+
+impl Posting {
+    fn count(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+/// A published document along with the statistics needed to answer frequency queries
+struct Document {
+    text: Arc<str>,
+    /// The byte span of each term of the document in `text`, indexed by term position
+    spans: Vec<(usize, usize)>,
+    metadata: Metadata,
+}
+
+impl Document {
+    fn word_count(&self) -> usize {
+        self.spans.len()
+    }
+
+    // The byte range of the `length` terms starting at term position `start`.
+    fn byte_range(&self, start: usize, length: usize) -> (usize, usize) {
+        (self.spans[start].0, self.spans[start + length - 1].1)
+    }
+
+    // Cut a snippet out of the text around the `length` terms starting at term position `start`,
+    // with up to `context` terms either side.
+    fn snippet(&self, start: usize, length: usize, context: usize) -> Snippet {
+        let (match_start, match_end) = self.byte_range(start, length);
+        let from = self.spans[start.saturating_sub(context)].0;
+        let last = (start + length - 1).saturating_add(context);
+        let to = self.spans[last.min(self.spans.len() - 1)].1;
+        Snippet {
+            start: match_start,
+            end: match_end,
+            before: self.text[from..match_start].to_string(),
+            matched: self.text[match_start..match_end].to_string(),
+            after: self.text[match_end..to].to_string(),
+        }
+    }
 }
 
 const BUCKETS: usize = 128;
 
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Database {
-    // TODO:
-    // Create a new empty archive. The map should have `BUCKETS` buckets.
+    // Create a new empty archive using the default configuration.
     pub fn new() -> Self {
+        Self::with_config(DatabaseConfig::default())
+    }
+
+    // Create a new empty archive with the given configuration. The map starts with `BUCKETS`
+    // buckets and grows as documents are added.
+    pub fn with_config(config: DatabaseConfig) -> Self {
         Database {
             reverse_index: ConcurrentMultiMap::new(BUCKETS),
+            dictionary: TermDictionary::new(),
             blob_store: Mutex::new(Vec::new()),
+            tombstones: RwLock::new(HashSet::new()),
+            max_ngram: config.max_ngram.max(1),
+            tokenizer: config.tokenizer,
+            k1: config.k1,
+            b: config.b,
+            max_expansions: config.max_expansions,
+            suggestion_distance: config.suggestion_distance,
+            storage: None,
         }
     }
 
-    // TODO:
-    // Publish a document to the archive in three steps:
-    // 1. Make a new unique identifier for the document
-    // 2. Split the document into words and map each word to the document's identifier in the
-    //    reverse index. For our purposes, using built-in String functionality to split on
-    //    whitespace is sufficient. It is up to you whether to also perform transformations like
-    //    converting to lowercase or removing numerals.
-    // 3. Add the document to the blob store
-    pub fn publish(&self, doc: String) -> usize {
+    // Open a persistent archive stored in `dir`, creating it if it doesn't exist. The archive is
+    // recovered by loading the latest snapshot and replaying the write-ahead log on top of it.
+    // Since documents are replayed in the order they were originally published, and deleted
+    // documents keep their slots, every document keeps its id. Documents deleted in the log are
+    // compacted straight away.
+    pub fn open(config: DatabaseConfig, dir: &Path) -> Result<Self, DatabaseError> {
+        let (storage, records) = Storage::open(dir)?;
+        let mut database = Self::with_config(config);
+        {
+            let mut blob_store = database.blob_store.lock().unwrap();
+            for record in records {
+                match record {
+                    Record::Publish(doc) => {
+                        database.insert(&mut blob_store, doc.into_owned());
+                    }
+                    Record::Delete(id) => {
+                        if let Some(slot) = blob_store.get_mut(id) {
+                            *slot = None;
+                            database.tombstones.write().unwrap().insert(id);
+                        }
+                    }
+                    Record::Tombstone => blob_store.push(None),
+                }
+            }
+        }
+        database.compact();
+        database.storage = Some(Mutex::new(storage));
+        Ok(database)
+    }
+
+    // Publish a document to the archive. If the archive is persistent, the document is durably
+    // appended to the write-ahead log first, and an error is returned if that fails.
+    pub fn publish(&self, doc: String) -> Result<usize, DatabaseError> {
+        let mut blob_store = self.blob_store.lock().unwrap();
+        if let Some(storage) = &self.storage {
+            let record = Record::Publish(Cow::Borrowed(&doc));
+            storage.lock().unwrap().append(&record)?;
+        }
+        Ok(self.insert(&mut blob_store, doc))
+    }
+
+    // Delete the document with the given id. The document is freed and removed from search
+    // results immediately, but its reverse index entries are only reclaimed by `compact`. The id
+    // is never reused. If the archive is persistent, the deletion is durably appended to the
+    // write-ahead log first.
+    pub fn delete(&self, id: usize) -> Result<(), DatabaseError> {
         let mut blob_store = self.blob_store.lock().unwrap();
+        live_document(&blob_store, id)?;
+        if let Some(storage) = &self.storage {
+            storage.lock().unwrap().append(&Record::Delete(id))?;
+        }
+        blob_store[id] = None;
+        self.tombstones.write().unwrap().insert(id);
+        Ok(())
+    }
+
+    // Remove the reverse index entries of deleted documents, and then drop their tombstones. The
+    // ids of the remaining documents are unchanged. Terms that were only used by the deleted
+    // documents are removed from the term dictionary as well. Returns the number of documents that
+    // were compacted.
+    pub fn compact(&self) -> usize {
+        let tombstones = self.tombstones.read().unwrap().clone();
+        if tombstones.is_empty() {
+            return 0;
+        }
+        let mut removed = HashSet::new();
+        self.reverse_index.retain(|term, posting| {
+            let keep = !tombstones.contains(&posting.doc);
+            if !keep {
+                removed.insert(term.clone());
+            }
+            keep
+        });
+        self.dictionary
+            .retain(|term| !removed.contains(term) || !self.reverse_index.get(term).is_empty());
+        self.tombstones
+            .write()
+            .unwrap()
+            .retain(|id| !tombstones.contains(id));
+        tombstones.len()
+    }
+
+    // Write a compacted snapshot of the archive and truncate the write-ahead log. Returns whether
+    // a snapshot was written, which is only the case for a persistent archive that has changed
+    // since its last snapshot.
+    pub fn snapshot(&self) -> Result<bool, DatabaseError> {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return Ok(false),
+        };
+        let blob_store = self.blob_store.lock().unwrap();
+        let mut storage = storage.lock().unwrap();
+        if !storage.has_changes() {
+            return Ok(false);
+        }
+        let records = blob_store.iter().map(|slot| match slot {
+            Some(document) => Record::Publish(Cow::Borrowed(&document.text[..])),
+            None => Record::Tombstone,
+        });
+        storage.write_snapshot(records)?;
+        Ok(true)
+    }
+
+    // Add a document to the archive in three steps:
+    // 1. Make a new unique identifier for the document
+    // 2. Split the document into terms with the tokenizer and collect the positions of every
+    //    n-gram of up to `max_ngram` terms. Each n-gram is then inserted into the reverse index
+    //    once, together with its positions.
+    // 3. Add the document to the blob store, along with the byte span of each term so that
+    //    positions can be mapped back to the text
+    fn insert(&self, blob_store: &mut Vec<Option<Document>>, doc: String) -> usize {
         let index = blob_store.len();
-        for word in doc.split_whitespace() {
-            self.reverse_index.set(word.to_string(), index);
+
+        // The header line is metadata rather than text, so its words are neither indexed nor
+        // counted
+        let body = split_header(&doc).map_or(0, |(_, body)| body);
+        let (words, spans): (Vec<String>, Vec<(usize, usize)>) = self
+            .tokenizer
+            .tokenize(&doc[body..])
+            .into_iter()
+            .map(|token| (token.text, (body + token.start, body + token.end)))
+            .unzip();
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        for n in 1..=self.max_ngram {
+            for (start, window) in words.windows(n).enumerate() {
+                positions.entry(window.join(" ")).or_default().push(start);
+            }
         }
-        blob_store.push(doc);
+        for (ngram, positions) in positions {
+            self.reverse_index.set(
+                ngram,
+                Posting {
+                    doc: index,
+                    positions,
+                },
+            );
+        }
+        // The terms are only added to the dictionary once they are in the reverse index, so that
+        // a concurrent `compact` can't see them without postings and drop them again
+        let vocabulary: HashSet<&String> = words.iter().collect();
+        self.dictionary.extend(vocabulary.into_iter().cloned());
+        let metadata = Metadata::from_header(&doc);
+        blob_store.push(Some(Document {
+            text: Arc::from(doc),
+            spans,
+            metadata,
+        }));
+
+        index
+    }
 
-        return index;
+    // Use the reverse index to get the set of documents that contain the given phrase, sorted by
+    // id.
+    pub fn search(&self, phrase: &str) -> Vec<usize> {
+        let mut ids: Vec<usize> = self
+            .postings(phrase)
+            .into_iter()
+            .map(|posting| posting.doc)
+            .collect();
+        ids.sort_unstable();
+        ids
     }
-    // TODO:
-    // Use the reverse index to get the set of documents that contain the given word.
-    pub fn search(&self, word: &str) -> Vec<usize> {
-        return self.reverse_index.get(word);
+
+    // Get one page of the documents that contain the given phrase: the first `limit` ids greater
+    // than `cursor`, or from the start if there is no cursor. A page holds at least one document,
+    // so that the cursor always moves forward. Pages are ordered by id, and the cursor for the
+    // next page is the last id on this one. Since ids only ever grow, paging through the results
+    // while documents are being published never skips or repeats a document; documents published
+    // in the meantime simply appear on later pages, and `total` counts the matches at the time
+    // each page was fetched.
+    pub fn search_page(&self, phrase: &str, limit: usize, cursor: Option<usize>) -> SearchPage {
+        let ids = self.search(phrase);
+        let start = match cursor {
+            Some(cursor) => ids.partition_point(|&id| id <= cursor),
+            None => 0,
+        };
+        let end = start.saturating_add(limit.max(1)).min(ids.len());
+        let page = ids[start..end].to_vec();
+        SearchPage {
+            next: page.last().copied().filter(|_| end < ids.len()),
+            total: ids.len(),
+            ids: page,
+        }
     }
-    // TODO:
-    // Retrieve the document with the given id from the blob store.
-    // Return None if the given id is invalid.
-    pub fn retrieve(&self, id: usize) -> Option<String> {
+
+    // Find the documents containing any term that matches the pattern, sorted by id. The pattern
+    // is matched against the term dictionary rather than tokenized, since tokenizing would strip
+    // out its wildcards, but it is lowercased if the tokenizer lowercases terms. The term of a
+    // fuzzy pattern has no wildcards, so it is tokenized like a search, and must be a single term.
+    // Returns an error if a regular expression is invalid, or if the pattern matches more than
+    // `max_expansions` terms.
+    pub fn pattern_search(&self, pattern: &TermPattern) -> Result<Vec<usize>, DatabaseError> {
+        let pattern = match pattern {
+            TermPattern::Fuzzy { term, max_distance } => match self.terms(term).as_slice() {
+                [term] => TermPattern::Fuzzy {
+                    term: term.clone(),
+                    max_distance: *max_distance,
+                },
+                _ => return Ok(Vec::new()),
+            },
+            _ if !self.tokenizer.lowercases() => pattern.clone(),
+            TermPattern::Prefix(prefix) => TermPattern::Prefix(prefix.to_lowercase()),
+            TermPattern::Wildcard(glob) => TermPattern::Wildcard(glob.to_lowercase()),
+            TermPattern::Regex(regex) => TermPattern::Regex(format!("(?i){}", regex)),
+        };
+        let mut documents = BTreeSet::new();
+        for term in self.dictionary.matches(&pattern, self.max_expansions)? {
+            documents.extend(
+                self.term_postings(&term)
+                    .into_iter()
+                    .map(|posting| posting.doc),
+            );
+        }
+        Ok(documents.into_iter().collect())
+    }
+
+    // Suggest what to search for instead of a word or phrase that matched nothing. A single word
+    // gets up to `SUGGESTIONS` terms within `suggestion_distance` edits of it, closest first, with
+    // ties going to the more common terms and then to the alphabetically first. A phrase gets at
+    // most one suggestion, which is corrected from left to right: each word that isn't in any
+    // document is replaced by the best of its suggestions that the corrected phrase so far can be
+    // followed by.
+    pub fn suggestions(&self, phrase: &str) -> Vec<String> {
+        let words = self.terms(phrase);
+        if let [word] = words.as_slice() {
+            return self.similar_terms(word);
+        }
+        let mut corrected: Vec<String> = Vec::new();
+        for word in &words {
+            if self.term_postings(word).is_empty() {
+                let replacement = self.similar_terms(word).into_iter().find(|term| {
+                    corrected.push(term.clone());
+                    let found = !self.phrase_postings(&corrected).is_empty();
+                    corrected.pop();
+                    found
+                });
+                corrected.push(replacement.unwrap_or_else(|| word.clone()));
+            } else {
+                corrected.push(word.clone());
+            }
+        }
+        if corrected == words || self.phrase_postings(&corrected).is_empty() {
+            return Vec::new();
+        }
+        vec![corrected.join(" ")]
+    }
+
+    // The live terms within `suggestion_distance` edits of a term, best first, as described by
+    // `suggestions`.
+    fn similar_terms(&self, word: &str) -> Vec<String> {
+        let mut similar: Vec<(usize, Reverse<usize>, String)> = self
+            .dictionary
+            .fuzzy(word, self.suggestion_distance)
+            .into_iter()
+            .filter(|(term, _)| term != word)
+            .filter_map(|(term, distance)| {
+                let occurrences: usize = self.term_postings(&term).iter().map(Posting::count).sum();
+                (occurrences > 0).then_some((distance, Reverse(occurrences), term))
+            })
+            .collect();
+        similar.sort();
+        similar
+            .into_iter()
+            .take(SUGGESTIONS)
+            .map(|(_, _, term)| term)
+            .collect()
+    }
+
+    // Find the documents matching a boolean query, sorted by id. Each word or phrase in the query
+    // is looked up like `search`, and the results are combined with set operations. NOT has to be
+    // evaluated against every live document, except directly under an AND, where it is cheaper
+    // to remove its matches from the other side.
+    pub fn query(&self, query: &Query) -> Vec<usize> {
+        self.evaluate(query).into_iter().collect()
+    }
+
+    fn evaluate(&self, query: &Query) -> BTreeSet<usize> {
+        match query {
+            Query::Term(phrase) => self.search(phrase).into_iter().collect(),
+            Query::Near {
+                left,
+                right,
+                distance,
+            } => self.near(left, right, *distance).into_iter().collect(),
+            Query::And(left, right) => match (left.as_ref(), right.as_ref()) {
+                (_, Query::Not(excluded)) => &self.evaluate(left) - &self.evaluate(excluded),
+                (Query::Not(excluded), _) => &self.evaluate(right) - &self.evaluate(excluded),
+                _ => &self.evaluate(left) & &self.evaluate(right),
+            },
+            Query::Or(left, right) => &self.evaluate(left) | &self.evaluate(right),
+            Query::Not(excluded) => {
+                let excluded = self.evaluate(excluded);
+                let blob_store = self.blob_store.lock().unwrap();
+                (0..blob_store.len())
+                    .filter(|id| blob_store[*id].is_some() && !excluded.contains(id))
+                    .collect()
+            }
+        }
+    }
+
+    // Find the documents in which the two phrases occur within `distance` terms of each other, in
+    // either order, sorted by id. The distance is counted from the last term of whichever phrase
+    // comes first to the first term of the other, so adjacent phrases are at distance 1, and
+    // occurrences that overlap don't count. For each occurrence of `left`, the occurrences of
+    // `right` that are close enough lie in two ranges of positions, which are found by binary
+    // search. The ranges are clamped rather than overflowing, so any distance is allowed.
+    pub fn near(&self, left: &str, right: &str, distance: usize) -> Vec<usize> {
+        let left_length = self.terms(left).len();
+        let right_length = self.terms(right).len();
+        let right: HashMap<usize, Vec<usize>> = self
+            .postings(right)
+            .into_iter()
+            .map(|posting| (posting.doc, posting.positions))
+            .collect();
+
+        self.postings(left)
+            .into_iter()
+            .filter(|posting| {
+                let others = match right.get(&posting.doc) {
+                    Some(others) => others,
+                    None => return false,
+                };
+                posting.positions.iter().any(|&start| {
+                    let last = start + left_length - 1;
+                    let after = any_between(others, last + 1, last.saturating_add(distance));
+                    let before = start >= right_length
+                        && any_between(
+                            others,
+                            (start + 1).saturating_sub(distance.saturating_add(right_length)),
+                            start - right_length,
+                        );
+                    after || before
+                })
+            })
+            .map(|posting| posting.doc)
+            .collect()
+    }
+
+    // Find the byte ranges of every occurrence of a phrase in the document with the given id, in
+    // the order they appear. Return an error if the given id is invalid or the document has been
+    // deleted.
+    pub fn highlights(
+        &self,
+        id: usize,
+        phrase: &str,
+    ) -> Result<Vec<(usize, usize)>, DatabaseError> {
+        let length = self.terms(phrase).len();
+        let positions = self
+            .postings(phrase)
+            .into_iter()
+            .find(|posting| posting.doc == id)
+            .map(|posting| posting.positions)
+            .unwrap_or_default();
+        let blob_store = self.blob_store.lock().unwrap();
+        let document = live_document(&blob_store, id)?;
+        Ok(positions
+            .into_iter()
+            .map(|start| document.byte_range(start, length))
+            .collect())
+    }
+
+    // Find the documents containing a phrase, sorted by id, along with up to `snippets` passages
+    // from each showing the phrase with `context` terms on either side, like the lines of a
+    // concordance. The passages are cut from the document's text using the term spans recorded
+    // when it was published, so they keep the document's original spelling and punctuation.
+    pub fn concordance(&self, phrase: &str, context: usize, snippets: usize) -> Vec<Hit> {
+        let length = self.terms(phrase).len();
+        let postings = self.postings(phrase);
+        let blob_store = self.blob_store.lock().unwrap();
+        postings
+            .into_iter()
+            .filter_map(|posting| {
+                let document = blob_store[posting.doc].as_ref()?;
+                Some(Hit {
+                    id: posting.doc,
+                    count: posting.count(),
+                    snippets: posting
+                        .positions
+                        .iter()
+                        .take(snippets)
+                        .map(|&start| document.snippet(start, length, context))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    // Rank the documents containing any of the terms of `query` by their BM25 score, and return
+    // the `limit` best, most relevant first. Ties are broken by id. Each document's score is the
+    // sum over the query's distinct terms of
+    //
+    //     idf * tf * (k1 + 1) / (tf + k1 * (1 - b + b * length / average length))
+    //
+    // where tf is how often the term occurs in the document, and idf = ln(1 + (N - df + 0.5) /
+    // (df + 0.5)) for N documents, df of which contain the term. The term frequencies come
+    // straight from the reverse index, and the lengths from the blob store.
+    pub fn ranked_search(&self, query: &str, limit: usize) -> Vec<ScoredDocument> {
+        let mut terms = self.terms(query);
+        terms.sort();
+        terms.dedup();
+        let postings: Vec<Vec<Posting>> =
+            terms.iter().map(|term| self.term_postings(term)).collect();
+
         let blob_store = self.blob_store.lock().unwrap();
-        if id >= blob_store.len() {
-            return None;
+        let (documents, total_length) = blob_store
+            .iter()
+            .flatten()
+            .fold((0, 0), |(count, length), document| {
+                (count + 1, length + document.word_count())
+            });
+        if documents == 0 {
+            return Vec::new();
         }
-        return Some(blob_store[id].clone());
+        let average_length = total_length.max(1) as f64 / documents as f64;
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for postings in postings {
+            let df = postings.len() as f64;
+            let idf = (1.0 + (documents as f64 - df + 0.5) / (df + 0.5)).ln();
+            for posting in postings {
+                let length = match &blob_store[posting.doc] {
+                    Some(document) => document.word_count() as f64,
+                    None => continue,
+                };
+                let tf = posting.count() as f64;
+                let norm = self.k1 * (1.0 - self.b + self.b * length / average_length);
+                *scores.entry(posting.doc).or_insert(0.0) +=
+                    idf * tf * (self.k1 + 1.0) / (tf + norm);
+            }
+        }
+
+        let mut ranked: Vec<ScoredDocument> = scores
+            .into_iter()
+            .map(|(id, score)| ScoredDocument { id, score })
+            .collect();
+        ranked.sort_by(|x, y| y.score.total_cmp(&x.score).then(x.id.cmp(&y.id)));
+        ranked.truncate(limit);
+        ranked
+    }
+
+    // Count how often the given phrase occurs in each document that contains it, both as a raw
+    // count and relative to the number of words in the document. The results are sorted by id.
+    pub fn frequency(&self, phrase: &str) -> Vec<Frequency> {
+        let postings = self.postings(phrase);
+        let blob_store = self.blob_store.lock().unwrap();
+        let mut frequencies: Vec<Frequency> = postings
+            .into_iter()
+            .filter_map(|posting| {
+                let document = blob_store[posting.doc].as_ref()?;
+                Some(Frequency {
+                    id: posting.doc,
+                    count: posting.count(),
+                    relative: posting.count() as f64 / document.word_count() as f64,
+                })
+            })
+            .collect();
+        frequencies.sort_by_key(|frequency| frequency.id);
+        frequencies
+    }
+
+    // Aggregate the relative frequency of the given phrase by publication year. For each year, the
+    // occurrences in every document published that year are summed and divided by the total
+    // number of words published that year. Documents without a known year are skipped, and the
+    // results are sorted by year.
+    pub fn timeline(&self, phrase: &str) -> Vec<TimelinePoint> {
+        let postings = self.postings(phrase);
+        let blob_store = self.blob_store.lock().unwrap();
+
+        let mut years: BTreeMap<i32, TimelinePoint> = BTreeMap::new();
+        for document in blob_store.iter().flatten() {
+            if let Some(year) = document.metadata.year {
+                let point = years.entry(year).or_insert(TimelinePoint {
+                    year,
+                    count: 0,
+                    word_count: 0,
+                    relative: 0.0,
+                });
+                point.word_count += document.word_count();
+            }
+        }
+        for posting in postings {
+            let year = blob_store[posting.doc]
+                .as_ref()
+                .and_then(|document| document.metadata.year);
+            if let Some(year) = year {
+                years.get_mut(&year).unwrap().count += posting.count();
+            }
+        }
+
+        years
+            .into_values()
+            .map(|mut point| {
+                if point.word_count > 0 {
+                    point.relative = point.count as f64 / point.word_count as f64;
+                }
+                point
+            })
+            .collect()
+    }
+
+    // Split text into terms with the database's tokenizer.
+    fn terms(&self, text: &str) -> Vec<String> {
+        self.tokenizer
+            .tokenize(text)
+            .into_iter()
+            .map(|token| token.text)
+            .collect()
     }
+
+    // Look up the postings of an n-gram that has already been split into terms, leaving out
+    // deleted documents.
+    fn term_postings(&self, ngram: &str) -> Vec<Posting> {
+        let mut postings = self.reverse_index.get(ngram);
+        let tombstones = self.tombstones.read().unwrap();
+        postings.retain(|posting| !tombstones.contains(&posting.doc));
+        postings
+    }
+
+    // Find the postings for the given phrase, leaving out deleted documents. The phrase is split
+    // into terms with the same tokenizer as documents.
+    fn postings(&self, phrase: &str) -> Vec<Posting> {
+        self.phrase_postings(&self.terms(phrase))
+    }
+
+    // Find the postings for a phrase that has already been split into terms. Phrases of up to
+    // `max_ngram` words are a single lookup. Longer phrases are split into overlapping
+    // `max_ngram`-word windows, and the phrase starts at a position if the window at offset `i`
+    // starts at that position plus `i`.
+    fn phrase_postings(&self, words: &[String]) -> Vec<Posting> {
+        if words.is_empty() {
+            return Vec::new();
+        }
+        if words.len() <= self.max_ngram {
+            return self.term_postings(&words.join(" "));
+        }
+
+        let windows: Vec<HashMap<usize, Vec<usize>>> = words
+            .windows(self.max_ngram)
+            .map(|window| {
+                self.term_postings(&window.join(" "))
+                    .into_iter()
+                    .map(|posting| (posting.doc, posting.positions))
+                    .collect()
+            })
+            .collect();
+        let (first, rest) = windows.split_first().unwrap();
+        let mut postings: Vec<Posting> = first
+            .iter()
+            .filter_map(|(&doc, starts)| {
+                let positions: Vec<usize> = starts
+                    .iter()
+                    .copied()
+                    .filter(|start| {
+                        rest.iter().enumerate().all(|(i, window)| {
+                            window.get(&doc).is_some_and(|positions| {
+                                positions.binary_search(&(start + i + 1)).is_ok()
+                            })
+                        })
+                    })
+                    .collect();
+                Some(Posting { doc, positions }).filter(|posting| posting.count() > 0)
+            })
+            .collect();
+        postings.sort_unstable_by_key(|posting| posting.doc);
+        postings
+    }
+
+    // Retrieve the metadata of the document with the given id from the blob store.
+    // Return an error if the given id is invalid or the document has been deleted.
+    pub fn metadata(&self, id: usize) -> Result<Metadata, DatabaseError> {
+        let blob_store = self.blob_store.lock().unwrap();
+        live_document(&blob_store, id).map(|document| document.metadata.clone())
+    }
+
+    // Retrieve the document with the given id from the blob store.
+    // Return an error if the given id is invalid or the document has been deleted.
+    pub fn retrieve(&self, id: usize) -> Result<String, DatabaseError> {
+        let blob_store = self.blob_store.lock().unwrap();
+        live_document(&blob_store, id).map(|document| document.text.to_string())
+    }
+
+    // Get a shared reference to the text of the document with the given id, without copying it.
+    // The text stays valid even if the document is deleted in the meantime. Return an error if the
+    // given id is invalid or the document has been deleted.
+    pub fn document(&self, id: usize) -> Result<Arc<str>, DatabaseError> {
+        let blob_store = self.blob_store.lock().unwrap();
+        live_document(&blob_store, id).map(|document| Arc::clone(&document.text))
+    }
+}
+
+// Look up the live document with the given id in the blob store.
+fn live_document(blob_store: &[Option<Document>], id: usize) -> Result<&Document, DatabaseError> {
+    match blob_store.get(id) {
+        Some(Some(document)) => Ok(document),
+        Some(None) => Err(DatabaseError::Deleted(id)),
+        None => Err(DatabaseError::NotFound(id)),
+    }
+}
+
+// Whether any of the sorted `positions` lies in `low..=high`.
+fn any_between(positions: &[usize], low: usize, high: usize) -> bool {
+    let i = positions.partition_point(|&position| position < low);
+    i < positions.len() && positions[i] <= high
 }
@@ -0,0 +1,214 @@
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+// The term dictionary is the vocabulary of the reverse index: every distinct term that has been
+// indexed, kept in sorted order. The reverse index is a hash map, so it can only answer exact
+// lookups. The dictionary answers the questions that need the terms in order, such as which terms
+// start with a given prefix, and the terms it finds are then looked up in the reverse index as
+// usual.
+
+/// A pattern matching terms in the dictionary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TermPattern {
+    /// Terms starting with the string, so `thou` matches "thou", "thousand" and "thought"
+    Prefix(String),
+    /// Terms matching a glob, where `?` matches any single character and `*` matches any run of
+    /// characters, so `wh?t` matches "what" and "whit"
+    Wildcard(String),
+    /// Terms matched in full by a regular expression
+    Regex(String),
+    /// Terms that can be turned into `term` with at most `max_distance` single-character
+    /// insertions, deletions or substitutions, so `vnto` within 1 matches "unto"
+    Fuzzy { term: String, max_distance: usize },
+}
+
+/// An error in a pattern, or a pattern that matches too much of the dictionary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    /// The regular expression could not be parsed
+    InvalidRegex(String),
+    /// The pattern matches more terms than the given limit
+    TooManyTerms(usize),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::InvalidRegex(err) => write!(f, "invalid regular expression: {}", err),
+            PatternError::TooManyTerms(limit) => {
+                write!(f, "pattern matches more than {} terms", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// Whether a term matches a pattern
+type Matcher<'a> = Box<dyn Fn(&str) -> bool + 'a>;
+
+/// The sorted set of terms in the reverse index
+#[derive(Debug, Default)]
+pub struct TermDictionary {
+    terms: RwLock<BTreeSet<String>>,
+}
+
+impl TermDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The number of terms in the dictionary
+    pub fn len(&self) -> usize {
+        self.terms.read().unwrap().len()
+    }
+
+    // Whether the dictionary contains no terms
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Add the given terms to the dictionary, taking the lock once for all of them.
+    pub fn extend<I: IntoIterator<Item = String>>(&self, terms: I) {
+        self.terms.write().unwrap().extend(terms);
+    }
+
+    // Keep only the terms for which `predicate` returns true.
+    pub fn retain<F: FnMut(&String) -> bool>(&self, predicate: F) {
+        self.terms.write().unwrap().retain(predicate);
+    }
+
+    // Find the terms matching `pattern`, in sorted order. Prefixes and wildcards only scan the
+    // range of terms that start with their literal prefix (for a wildcard, everything before its
+    // first `?` or `*`), while a regular expression has to be tried against every term. Fuzzy
+    // patterns are matched by `fuzzy`. Returns an error rather than a partial list if more than
+    // `limit` terms match.
+    pub fn matches(
+        &self,
+        pattern: &TermPattern,
+        limit: usize,
+    ) -> Result<Vec<String>, PatternError> {
+        let (prefix, matcher): (&str, Matcher) = match pattern {
+            TermPattern::Prefix(prefix) => (prefix, Box::new(|_| true)),
+            TermPattern::Wildcard(glob) => {
+                let literal = &glob[..glob.find(['?', '*']).unwrap_or(glob.len())];
+                (literal, Box::new(move |term| glob_match(glob, term)))
+            }
+            TermPattern::Regex(pattern) => {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|err| PatternError::InvalidRegex(err.to_string()))?;
+                ("", Box::new(move |term| regex.is_match(term)))
+            }
+            TermPattern::Fuzzy { term, max_distance } => {
+                let matches = self.fuzzy(term, *max_distance);
+                if matches.len() > limit {
+                    return Err(PatternError::TooManyTerms(limit));
+                }
+                return Ok(matches.into_iter().map(|(term, _)| term).collect());
+            }
+        };
+
+        let terms = self.terms.read().unwrap();
+        let mut matches = Vec::new();
+        let candidates = terms
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|term| term.starts_with(prefix));
+        for term in candidates {
+            if matcher(term) {
+                if matches.len() == limit {
+                    return Err(PatternError::TooManyTerms(limit));
+                }
+                matches.push(term.clone());
+            }
+        }
+        Ok(matches)
+    }
+
+    // Find the terms within `max_distance` edits of `term`, in sorted order, along with their edit
+    // distances. This walks the sorted terms as if they were a trie, which makes the edit distance
+    // table behave like a Levenshtein automaton: row `i` of the table holds the distances from
+    // each prefix of `term` to the first `i` characters of the candidate, and only depends on
+    // those characters. Each candidate shares a prefix with the one before it, so the rows for
+    // that prefix are kept and only the rows for the rest of the candidate are computed. Once
+    // every entry of a row exceeds `max_distance`, no term starting with that prefix can match,
+    // and they are all skipped.
+    pub fn fuzzy(&self, term: &str, max_distance: usize) -> Vec<(String, usize)> {
+        let target: Vec<char> = term.chars().collect();
+        let terms = self.terms.read().unwrap();
+        let mut rows: Vec<Vec<usize>> = vec![(0..=target.len()).collect()];
+        let mut previous: Vec<char> = Vec::new();
+        let mut dead_prefix: Option<String> = None;
+        let mut matches = Vec::new();
+        for candidate in terms.iter() {
+            if let Some(prefix) = &dead_prefix {
+                if candidate.starts_with(prefix.as_str()) {
+                    continue;
+                }
+                dead_prefix = None;
+            }
+            let chars: Vec<char> = candidate.chars().collect();
+            let shared = previous
+                .iter()
+                .zip(&chars)
+                .take_while(|(a, b)| a == b)
+                .count();
+            rows.truncate(shared + 1);
+            for (i, &c) in chars.iter().enumerate().skip(shared) {
+                let above = &rows[i];
+                let mut row = vec![above[0] + 1];
+                for (j, &t) in target.iter().enumerate() {
+                    let substitute = above[j] + usize::from(t != c);
+                    row.push(substitute.min(above[j + 1] + 1).min(row[j] + 1));
+                }
+                let exhausted = row.iter().all(|&distance| distance > max_distance);
+                rows.push(row);
+                if exhausted {
+                    dead_prefix = Some(chars[..=i].iter().collect());
+                    break;
+                }
+            }
+            previous = chars;
+            if dead_prefix.is_none() {
+                let distance = rows.last().unwrap()[target.len()];
+                if distance <= max_distance {
+                    matches.push((candidate.clone(), distance));
+                }
+            }
+        }
+        matches
+    }
+}
+
+// Whether `text` matches the glob `pattern` in full. A `*` first tries to match nothing, and
+// whenever the rest of the pattern then fails to match, the most recent `*` is extended by one
+// more character and the match resumes from there.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -1,6 +1,10 @@
+pub mod analysis;
 pub mod client;
 pub mod database;
+pub mod dictionary;
 pub mod message;
 pub mod multimap;
 pub mod pool;
+pub mod query;
 pub mod server;
+mod storage;
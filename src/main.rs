@@ -1,6 +1,15 @@
 use clap::{Parser, Subcommand};
-use ngram::client::Client;
-use ngram::server::Server;
+use ngram::analysis::{load_stopwords, Numerals, StandardTokenizer};
+use ngram::client::{Client, ClientError};
+use ngram::database::{DatabaseConfig, Hit, TextRange};
+use ngram::dictionary::TermPattern;
+use ngram::message::{Limits, Response};
+use ngram::pool::PoolConfig;
+use ngram::server::{Server, ServerConfig};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 // TODO:
 // Fill out the `Args` struct to parse the command line arguments. You may find clap "subcommands"
@@ -28,14 +37,256 @@ enum Mode {
     Server {
         /// Port of the server
         server_port: u16,
+
+        /// Longest n-gram to store in the reverse index
+        #[arg(long, default_value_t = 1)]
+        max_ngram: usize,
+
+        /// Directory to persist the database in; kept in memory only if omitted
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+
+        /// Seconds between snapshots of a persistent database
+        #[arg(long, default_value_t = 60)]
+        snapshot_interval: u64,
+
+        /// Seconds a client connection may stay idle before it is closed
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        idle_timeout: u64,
+
+        /// Seconds a response may take to write before the connection is closed
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+        write_timeout: u64,
+
+        /// Most client connections to serve at once
+        #[arg(long, default_value_t = 256)]
+        max_connections: usize,
+
+        /// Largest request frame to accept, in bytes
+        #[arg(long)]
+        max_frame: Option<u32>,
+
+        /// Largest document that may be published, in bytes
+        #[arg(long)]
+        max_document: Option<usize>,
+
+        /// Largest word or phrase that may be searched for, in bytes
+        #[arg(long)]
+        max_word: Option<usize>,
+
+        /// Most edits a fuzzy search may allow
+        #[arg(long)]
+        max_distance: Option<usize>,
+
+        /// Leave the words in this file (one per line) out of the index; uses data/words.txt if
+        /// no file is given
+        #[arg(long, num_args = 0..=1, default_missing_value = "data/words.txt")]
+        stopwords: Option<PathBuf>,
+
+        /// Index plural words under their singular form
+        #[arg(long)]
+        stem: bool,
+
+        /// Leave numbers out of the index
+        #[arg(long)]
+        drop_numerals: bool,
+
+        /// BM25 term frequency saturation for ranked search
+        #[arg(long, default_value_t = 1.2)]
+        k1: f64,
+
+        /// BM25 document length normalization for ranked search, from 0 to 1
+        #[arg(long, default_value_t = 0.75)]
+        b: f64,
+
+        /// Most terms a prefix, wildcard, regex or fuzzy search may match
+        #[arg(long, default_value_t = 4096)]
+        max_expansions: usize,
+
+        /// Most edits between a word that matched nothing and the words suggested instead
+        #[arg(long, default_value_t = 2)]
+        suggestion_distance: usize,
+
+        /// Number of threads processing requests when the server is quiet
+        #[arg(long, default_value_t = 4)]
+        min_workers: usize,
+
+        /// Most threads processing requests when the server is busy
+        #[arg(long, default_value_t = 16)]
+        max_workers: usize,
+
+        /// Seconds an extra thread may sit idle before it exits
+        #[arg(long, default_value_t = 60)]
+        keep_alive: u64,
+
+        /// Most requests that may wait for a free thread before the server reports that it is
+        /// overloaded
+        #[arg(long, default_value_t = 1024)]
+        queue_capacity: usize,
+
+        /// Index words exactly as they appear, split on whitespace only
+        #[arg(long, conflicts_with_all = ["stopwords", "stem", "drop_numerals"])]
+        raw: bool,
     },
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Publish { path: String },
-    Search { word: String },
-    Retrieve { id: usize },
+    Publish {
+        path: String,
+    },
+    Search {
+        /// Match terms starting with the given prefix, e.g. 'thou'
+        #[arg(long, group = "pattern")]
+        prefix: bool,
+
+        /// Match terms against a glob where '?' is any character and '*' any run, e.g. 'wh?t'
+        #[arg(long, group = "pattern")]
+        wildcard: bool,
+
+        /// Match terms against a regular expression, e.g. 'th(ee|ou)'
+        #[arg(long, group = "pattern")]
+        regex: bool,
+
+        /// Return at most this many documents, as one page of the results
+        #[arg(long, conflicts_with = "pattern")]
+        limit: Option<usize>,
+
+        /// Return the page after this cursor, printed with the previous page
+        #[arg(long, requires = "limit")]
+        after: Option<usize>,
+
+        /// Match terms within this many edits of the word, e.g. 'vnto'
+        #[arg(long, group = "pattern", value_name = "DISTANCE", num_args = 0..=1, default_missing_value = "2")]
+        fuzzy: Option<usize>,
+
+        /// Word or phrase to search for, or a single pattern
+        #[arg(required = true, num_args = 1..)]
+        words: Vec<String>,
+    },
+    Retrieve {
+        /// Only retrieve the bytes from START up to but not including END, e.g. '0-1024'
+        #[arg(long, group = "range", value_name = "START-END", value_parser = parse_range)]
+        bytes: Option<(usize, usize)>,
+
+        /// Only retrieve the lines FIRST to LAST, counting from 1, e.g. '10-20'
+        #[arg(long, group = "range", value_name = "FIRST-LAST", value_parser = parse_range)]
+        lines: Option<(usize, usize)>,
+
+        /// Stream the document to standard output in chunks rather than in a single response
+        #[arg(long)]
+        stream: bool,
+
+        id: usize,
+    },
+    Frequency {
+        /// Word or phrase to count
+        #[arg(required = true, num_args = 1..)]
+        words: Vec<String>,
+    },
+    Metadata {
+        id: usize,
+    },
+    Timeline {
+        /// Word or phrase to chart by publication year
+        #[arg(required = true, num_args = 1..)]
+        words: Vec<String>,
+    },
+    Delete {
+        id: usize,
+    },
+    RankedSearch {
+        /// Number of documents to return
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+
+        /// Words to rank documents by
+        #[arg(required = true, num_args = 1..)]
+        words: Vec<String>,
+    },
+    Concordance {
+        /// Number of words of context to show on either side of each occurrence
+        #[arg(long, default_value_t = 5)]
+        context: usize,
+
+        /// Number of occurrences to show from each document
+        #[arg(long, default_value_t = 3)]
+        snippets: usize,
+
+        /// Word or phrase to search for
+        #[arg(required = true, num_args = 1..)]
+        words: Vec<String>,
+    },
+    Query {
+        /// Boolean query, e.g. '(witch OR witches) AND NOT hamlet'
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+    },
+    /// Show how busy the server is
+    Stats,
+}
+
+// The exit code of the client when a request fails. Errors from the server exit with 10 plus the
+// error's code, so scripts can tell them apart without parsing the message.
+fn exit_code(err: &ClientError) -> i32 {
+    match err {
+        ClientError::Connect(_) => 3,
+        ClientError::Io(_) => 4,
+        ClientError::Decode(_) => 5,
+        ClientError::Incompatible(_) => 6,
+        ClientError::Server(err) => (10 + err.code.to_u16() as i32).min(255),
+    }
+}
+
+// Parse a range given on the command line as two numbers separated by a dash.
+fn parse_range(range: &str) -> Result<(usize, usize), String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format!("expected a range like 10-20, got '{}'", range))?;
+    let parse = |number: &str| {
+        number
+            .trim()
+            .parse::<usize>()
+            .map_err(|err| format!("invalid number '{}': {}", number, err))
+    };
+    Ok((parse(start)?, parse(end)?))
+}
+
+// Stream part of a document to standard output as it arrives, exiting the way a failed request
+// would if the document doesn't exist or the stream is cut off.
+fn stream_document(client: &Client, id: usize, range: TextRange) {
+    let result = client.retrieve_stream(id, range).and_then(|mut reader| {
+        io::copy(&mut reader, &mut io::stdout().lock()).map_err(ClientError::Io)
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+// Print the hits of a concordance search with one occurrence per line, lined up so that the
+// occurrences form a column with their context either side. Line breaks in the context are shown
+// as spaces.
+fn print_concordance(hits: &[Hit]) {
+    let flatten = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let width = hits
+        .iter()
+        .flat_map(|hit| &hit.snippets)
+        .map(|snippet| flatten(&snippet.before).chars().count())
+        .max()
+        .unwrap_or(0);
+    for hit in hits {
+        println!("Document {} ({} occurrences)", hit.id, hit.count);
+        for snippet in &hit.snippets {
+            println!(
+                "  {:>width$} [{}] {}",
+                flatten(&snippet.before),
+                flatten(&snippet.matched),
+                flatten(&snippet.after),
+                width = width
+            );
+        }
+    }
 }
 
 // TODO:
@@ -51,33 +302,161 @@ fn main() {
             command,
         } => {
             let client = Client::new(&server_address, server_port);
-            match command {
-                Command::Publish { path } => {
-                    let response = client.publish_from_path(&path);
-                    match response {
-                        Some(r) => println!("{:?}", r),
-                        None => println!("none"),
+            let response = match command {
+                Command::Publish { path } => client.publish_from_path(&path),
+                Command::Search {
+                    prefix,
+                    wildcard,
+                    regex,
+                    fuzzy,
+                    limit,
+                    after,
+                    words,
+                } => {
+                    let word = words.join(" ");
+                    if let Some(limit) = limit {
+                        client.search_page(&word, limit, after)
+                    } else if let Some(max_distance) = fuzzy {
+                        client.pattern_search(TermPattern::Fuzzy {
+                            term: word,
+                            max_distance,
+                        })
+                    } else if prefix {
+                        client.pattern_search(TermPattern::Prefix(word))
+                    } else if wildcard {
+                        client.pattern_search(TermPattern::Wildcard(word))
+                    } else if regex {
+                        client.pattern_search(TermPattern::Regex(word))
+                    } else {
+                        client.search(&word)
                     }
                 }
-                Command::Search { word } => {
-                    let response = client.search(&word);
-                    match response {
-                        Some(r) => println!("{:?}", r),
-                        None => println!("none"),
+                Command::Retrieve {
+                    bytes,
+                    lines,
+                    stream,
+                    id,
+                } => {
+                    let range = match (bytes, lines) {
+                        (Some((start, end)), _) => Some(TextRange::Bytes { start, end }),
+                        (_, Some((first, last))) => Some(TextRange::Lines { first, last }),
+                        _ => None,
+                    };
+                    if stream || range.is_some() {
+                        stream_document(&client, id, range.unwrap_or(TextRange::All));
+                        return;
                     }
+                    client.retrieve(id)
                 }
-                Command::Retrieve { id } => {
-                    let response = client.retrieve(id);
-                    match response {
-                        Some(r) => println!("{:?}", r),
-                        None => println!("none"),
-                    }
+                Command::Frequency { words } => client.frequency(&words.join(" ")),
+                Command::Metadata { id } => client.metadata(id),
+                Command::Timeline { words } => client.timeline(&words.join(" ")),
+                Command::Delete { id } => client.delete(id),
+                Command::RankedSearch { limit, words } => {
+                    client.ranked_search(&words.join(" "), limit)
+                }
+                Command::Concordance {
+                    context,
+                    snippets,
+                    words,
+                } => client.concordance(&words.join(" "), context, snippets),
+                Command::Query { query } => client.query(&query.join(" ")),
+                Command::Stats => client.stats(),
+            };
+            match response {
+                Ok(Response::ConcordanceSuccess(hits)) => print_concordance(&hits),
+                Ok(Response::NoMatches(suggestions)) => {
+                    println!("No matches. Did you mean: {}?", suggestions.join(", "))
+                }
+                Ok(r) => println!("{:?}", r),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(exit_code(&err));
                 }
             }
         }
-        Mode::Server { server_port } => {
-            let server = Server::new();
-            server.run(server_port);
+        Mode::Server {
+            server_port,
+            max_ngram,
+            data_dir,
+            snapshot_interval,
+            idle_timeout,
+            write_timeout,
+            max_connections,
+            max_frame,
+            max_document,
+            max_word,
+            max_distance,
+            stopwords,
+            stem,
+            drop_numerals,
+            k1,
+            b,
+            max_expansions,
+            suggestion_distance,
+            min_workers,
+            max_workers,
+            keep_alive,
+            queue_capacity,
+            raw,
+        } => {
+            let stopwords = match stopwords {
+                Some(path) => match load_stopwords(&path) {
+                    Ok(stopwords) => stopwords,
+                    Err(err) => {
+                        eprintln!("Failed to read stopwords from {}: {}", path.display(), err);
+                        std::process::exit(1);
+                    }
+                },
+                None => Default::default(),
+            };
+            let tokenizer = StandardTokenizer {
+                lowercase: !raw,
+                strip_punctuation: !raw,
+                numerals: if drop_numerals {
+                    Numerals::Drop
+                } else {
+                    Numerals::Keep
+                },
+                stopwords,
+                stem,
+            };
+            let defaults = Limits::default();
+            let config = ServerConfig {
+                database: DatabaseConfig {
+                    max_ngram,
+                    tokenizer: Arc::new(tokenizer),
+                    k1,
+                    b,
+                    max_expansions,
+                    suggestion_distance,
+                },
+                data_dir,
+                snapshot_interval: Duration::from_secs(snapshot_interval),
+                idle_timeout: Duration::from_secs(idle_timeout),
+                write_timeout: Duration::from_secs(write_timeout),
+                max_connections,
+                limits: Limits {
+                    max_frame: max_frame.map_or(defaults.max_frame, |max| max as usize),
+                    max_document: max_document.unwrap_or(defaults.max_document),
+                    max_word: max_word.unwrap_or(defaults.max_word),
+                    max_distance: max_distance.unwrap_or(defaults.max_distance),
+                },
+                pool: PoolConfig {
+                    min_workers,
+                    max_workers,
+                    keep_alive: Duration::from_secs(keep_alive),
+                    queue_capacity,
+                    ..PoolConfig::default()
+                },
+            };
+            match Server::with_config(config) {
+                Ok(server) => server.run(server_port),
+                Err(err) => {
+                    eprintln!("Failed to start server: {}", err);
+                    std::process::exit(1);
+                }
+            }
         }
     }
 }
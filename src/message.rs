@@ -1,12 +1,243 @@
+use crate::database::{
+    DatabaseError, Frequency, Hit, Metadata, ScoredDocument, SearchPage, Snippet, TextRange,
+    TimelinePoint,
+};
+use crate::dictionary::TermPattern;
+use std::fmt;
+use std::io::{self, Read};
+
+/// The bytes that start every handshake
+pub const MAGIC: &[u8; 4] = b"NGRM";
+/// The newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest protocol version this build speaks. Version 1, which had no handshake and
+/// encoded integers with the width of the host's `usize`, is not supported.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Capability bit for `Request::Delete`
+pub const CAP_DELETE: u32 = 1 << 0;
+/// Capability bit for `Request::Timeline` and `Request::Metadata`
+pub const CAP_TIMELINE: u32 = 1 << 1;
+/// Capability bit for `Request::RankedSearch`
+pub const CAP_RANKED_SEARCH: u32 = 1 << 2;
+/// Capability bit for `Request::Query`
+pub const CAP_QUERY: u32 = 1 << 3;
+/// Capability bit for `Request::Concordance`
+pub const CAP_CONCORDANCE: u32 = 1 << 4;
+/// Capability bit for `Request::PatternSearch`
+pub const CAP_PATTERN_SEARCH: u32 = 1 << 5;
+/// Capability bit for `Response::NoMatches`
+pub const CAP_SUGGESTIONS: u32 = 1 << 6;
+/// Capability bit for `Request::SearchPage`
+pub const CAP_PAGINATION: u32 = 1 << 7;
+/// Capability bit for `Request::RetrieveStream`
+pub const CAP_STREAMING: u32 = 1 << 8;
+/// Capability bit for `Request::Stats`
+pub const CAP_STATS: u32 = 1 << 9;
+/// The capabilities that this build supports
+pub const CAPABILITIES: u32 = CAP_DELETE
+    | CAP_TIMELINE
+    | CAP_RANKED_SEARCH
+    | CAP_QUERY
+    | CAP_CONCORDANCE
+    | CAP_PATTERN_SEARCH
+    | CAP_SUGGESTIONS
+    | CAP_PAGINATION
+    | CAP_STREAMING
+    | CAP_STATS;
+
+// Before any frames are sent, the client and the server each send a `Hello`, starting with the
+// client. Each side then picks the newest protocol version that both support, and the features
+// that both support. If they have no version in common, the connection is closed. Since a
+// version 1 peer sends a frame length where the magic should be, it is refused straight away
+// rather than misread.
+
+/// The handshake message that each side sends when a connection is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    /// The oldest protocol version the sender speaks
+    pub min_version: u16,
+    /// The newest protocol version the sender speaks
+    pub max_version: u16,
+    /// A bitmap of the optional features the sender supports, made of the `CAP_` constants
+    pub capabilities: u32,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }
+    }
+}
+
+impl Hello {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(self.min_version.to_be_bytes().iter());
+        bytes.extend(self.max_version.to_be_bytes().iter());
+        bytes.extend(self.capabilities.to_be_bytes().iter());
+        bytes
+    }
+
+    pub fn from_bytes<R: Read>(mut reader: R) -> Result<Self, DecodeError> {
+        let mut bytes = [0; 12];
+        reader.read_exact(&mut bytes)?;
+        if &bytes[0..4] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        Ok(Hello {
+            min_version: u16::from_be_bytes([bytes[4], bytes[5]]),
+            max_version: u16::from_be_bytes([bytes[6], bytes[7]]),
+            capabilities: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+        })
+    }
+
+    // Agree on a protocol version and capabilities with a peer that sent `other`. Returns the
+    // newest version both sides speak along with the capabilities both sides support, or `None`
+    // if there is no version in common.
+    pub fn negotiate(&self, other: &Hello) -> Option<(u16, u32)> {
+        let version = self.max_version.min(other.max_version);
+        if version < self.min_version.max(other.min_version) {
+            return None;
+        }
+        Some((version, self.capabilities & other.capabilities))
+    }
+}
+
+/// The largest sizes of untrusted input that a server will accept
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The longest message a frame may carry, in bytes. Frames can't be longer than `u32::MAX`
+    /// bytes, so a larger limit is treated as `u32::MAX`.
+    pub max_frame: usize,
+    /// The longest document that may be published, in bytes
+    pub max_document: usize,
+    /// The longest word, phrase or query that may be searched for, in bytes
+    pub max_word: usize,
+    /// The most edits a fuzzy search may allow. The cost of a fuzzy search grows quickly with
+    /// its distance, since fewer terms of the dictionary can be ruled out early.
+    pub max_distance: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame: 32 * 1024 * 1024,
+            max_document: 16 * 1024 * 1024,
+            max_word: 4 * 1024,
+            max_distance: 3,
+        }
+    }
+}
+
+/// The reason a message could not be decoded
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The input ended in the middle of the message
+    Truncated,
+    /// Reading the input failed
+    Io(io::Error),
+    /// The message starts with a tag that doesn't name any kind of message
+    UnknownTag(u8),
+    /// A string in the message is not valid UTF-8
+    InvalidUtf8,
+    /// A length in the message is larger than the corresponding limit
+    TooLarge {
+        field: &'static str,
+        length: u64,
+        limit: usize,
+    },
+    /// The message is followed by extra bytes that are part of the same frame
+    TrailingBytes(usize),
+    /// The message doesn't follow the format of any message
+    Malformed,
+    /// The peer doesn't speak this protocol, since its handshake doesn't start with `MAGIC`
+    BadMagic,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::Io(err) => write!(f, "failed to read message: {}", err),
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message tag {}", tag),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::TooLarge {
+                field,
+                length,
+                limit,
+            } => write!(
+                f,
+                "{} is {} bytes long, but at most {} bytes are allowed",
+                field, length, limit
+            ),
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "message is followed by {} unexpected bytes", count)
+            }
+            DecodeError::Malformed => write!(f, "message is malformed"),
+            DecodeError::BadMagic => write!(f, "peer does not speak the ngram protocol"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => DecodeError::Truncated,
+            _ => DecodeError::Io(err),
+        }
+    }
+}
+
 /// A request from the client to the server
 #[derive(Debug, PartialEq)]
 pub enum Request {
     /// Add the document `doc` to the archive
     Publish { doc: String },
-    /// Search for the word `word` in the archive
+    /// Search for the word or phrase `word` in the archive
     Search { word: String },
     /// Retrieve the document with the index `id` from the archive
     Retrieve { id: usize },
+    /// Count how often the word or phrase `word` occurs in each document of the archive
+    Frequency { word: String },
+    /// Retrieve the metadata of the document with the index `id`
+    Metadata { id: usize },
+    /// Aggregate the relative frequency of the word or phrase `word` by publication year
+    Timeline { word: String },
+    /// Delete the document with the index `id` from the archive
+    Delete { id: usize },
+    /// Find the `limit` documents most relevant to the words in `word`
+    RankedSearch { word: String, limit: usize },
+    /// Find the documents matching the boolean query `query`, written in the language described
+    /// in `query`
+    Query { query: String },
+    /// Search for the word or phrase `word`, returning up to `snippets` occurrences from each
+    /// matching document with `context` words on either side
+    Concordance {
+        word: String,
+        context: usize,
+        snippets: usize,
+    },
+    /// Search for the documents containing any term that matches `pattern`
+    PatternSearch { pattern: TermPattern },
+    /// Search for the word or phrase `word`, returning at most `limit` documents from after
+    /// `cursor`, which is the `next` cursor of the previous page
+    SearchPage {
+        word: String,
+        limit: usize,
+        cursor: Option<usize>,
+    },
+    /// Retrieve the part `range` of the document with the index `id`. The server answers with a
+    /// series of `Response::Chunk`s carrying the request's id, rather than a single response, so
+    /// this request should be sent with `Client::retrieve_stream` rather than in a pipeline.
+    RetrieveStream { id: usize, range: TextRange },
+    /// Report how busy the server is
+    Stats,
 }
 impl Request {
     // Convert the request `self` into a byte vector. See the assignment handout for suggestions on
@@ -16,85 +247,246 @@ impl Request {
             Self::Publish { doc } => {
                 let mut bytes = vec![0];
                 let length = doc.len();
-                bytes.extend(length.to_be_bytes().iter());
+                bytes.extend((length as u64).to_be_bytes().iter());
                 bytes.extend(doc.as_bytes());
-                return bytes;
+                bytes
             }
             Self::Search { word } => {
                 let mut bytes = vec![1];
                 let length = word.len();
-                bytes.extend(length.to_be_bytes().iter());
+                bytes.extend((length as u64).to_be_bytes().iter());
                 bytes.extend(word.as_bytes());
-                return bytes;
+                bytes
             }
             Self::Retrieve { id } => {
                 let mut bytes = vec![2];
-                bytes.extend(id.to_be_bytes().iter());
-                return bytes;
+                bytes.extend((*id as u64).to_be_bytes().iter());
+                bytes
+            }
+            Self::Frequency { word } => {
+                let mut bytes = vec![3];
+                let length = word.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(word.as_bytes());
+                bytes
+            }
+            Self::Metadata { id } => {
+                let mut bytes = vec![4];
+                bytes.extend((*id as u64).to_be_bytes().iter());
+                bytes
             }
+            Self::Timeline { word } => {
+                let mut bytes = vec![5];
+                let length = word.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(word.as_bytes());
+                bytes
+            }
+            Self::Delete { id } => {
+                let mut bytes = vec![6];
+                bytes.extend((*id as u64).to_be_bytes().iter());
+                bytes
+            }
+            Self::RankedSearch { word, limit } => {
+                let mut bytes = vec![7];
+                let length = word.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(word.as_bytes());
+                bytes.extend((*limit as u64).to_be_bytes().iter());
+                bytes
+            }
+            Self::Query { query } => {
+                let mut bytes = vec![8];
+                let length = query.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(query.as_bytes());
+                bytes
+            }
+            Self::Concordance {
+                word,
+                context,
+                snippets,
+            } => {
+                let mut bytes = vec![9];
+                let length = word.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(word.as_bytes());
+                bytes.extend((*context as u64).to_be_bytes().iter());
+                bytes.extend((*snippets as u64).to_be_bytes().iter());
+                bytes
+            }
+            Self::PatternSearch { pattern } => {
+                let mut bytes = vec![10];
+                let (kind, pattern, max_distance) = match pattern {
+                    TermPattern::Prefix(prefix) => (0, prefix, None),
+                    TermPattern::Wildcard(glob) => (1, glob, None),
+                    TermPattern::Regex(regex) => (2, regex, None),
+                    TermPattern::Fuzzy { term, max_distance } => (3, term, Some(*max_distance)),
+                };
+                bytes.push(kind);
+                let length = pattern.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(pattern.as_bytes());
+                if let Some(max_distance) = max_distance {
+                    bytes.extend((max_distance as u64).to_be_bytes().iter());
+                }
+                bytes
+            }
+            Self::SearchPage {
+                word,
+                limit,
+                cursor,
+            } => {
+                let mut bytes = vec![11];
+                let length = word.len();
+                bytes.extend((length as u64).to_be_bytes().iter());
+                bytes.extend(word.as_bytes());
+                bytes.extend((*limit as u64).to_be_bytes().iter());
+                write_optional_usize(&mut bytes, *cursor);
+                bytes
+            }
+            Self::RetrieveStream { id, range } => {
+                let mut bytes = vec![12];
+                bytes.extend((*id as u64).to_be_bytes().iter());
+                let (kind, from, to) = match *range {
+                    TextRange::All => (0, 0, 0),
+                    TextRange::Bytes { start, end } => (1, start, end),
+                    TextRange::Lines { first, last } => (2, first, last),
+                };
+                bytes.push(kind);
+                if kind != 0 {
+                    bytes.extend((from as u64).to_be_bytes().iter());
+                    bytes.extend((to as u64).to_be_bytes().iter());
+                }
+                bytes
+            }
+            Self::Stats => vec![13],
         }
     }
     // TODO:
     // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
     // `from_bytes` should return the original request. If the request is invalid, return `None`.
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
-        let mut response_type = [0; 1];
-        let result = reader.read_exact(&mut response_type);
-        if result.is_err() {
-            return None;
-        }
-
-        match response_type[0] {
-            0 => {
-                let mut length_buffer = [0; 8];
-                let length_result = reader.read_exact(&mut length_buffer);
-                if length_result.is_err() {
-                    return None;
-                }
-                let length = usize::from_be_bytes(length_buffer);
-
-                let mut string_buffer = vec![0; length.into()];
-                let read_result = reader.read_exact(&mut string_buffer);
-                if read_result.is_err() {
-                    return None;
-                }
+    pub fn from_bytes<R: Read>(reader: R) -> Option<Self> {
+        Self::decode(reader, &Limits::default()).ok()
+    }
 
-                let ret = Self::Publish {
-                    doc: String::from_utf8(string_buffer).unwrap(),
+    // Read a request from `reader`, which may have been sent by anyone, so nothing about it is
+    // trusted. Every length is checked against `limits` before anything is allocated for it, and
+    // strings must be valid UTF-8. Returns an error describing the first problem found.
+    pub fn decode<R: Read>(mut reader: R, limits: &Limits) -> Result<Self, DecodeError> {
+        let tag = read_u8(&mut reader)?;
+        match tag {
+            0 => Ok(Self::Publish {
+                doc: read_string(&mut reader, "document", limits.max_document)?,
+            }),
+            1 => Ok(Self::Search {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+            }),
+            2 => Ok(Self::Retrieve {
+                id: read_usize(&mut reader)?,
+            }),
+            3 => Ok(Self::Frequency {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+            }),
+            4 => Ok(Self::Metadata {
+                id: read_usize(&mut reader)?,
+            }),
+            5 => Ok(Self::Timeline {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+            }),
+            6 => Ok(Self::Delete {
+                id: read_usize(&mut reader)?,
+            }),
+            7 => Ok(Self::RankedSearch {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+                limit: read_usize(&mut reader)?,
+            }),
+            8 => Ok(Self::Query {
+                query: read_string(&mut reader, "query", limits.max_word)?,
+            }),
+            9 => Ok(Self::Concordance {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+                context: read_usize(&mut reader)?,
+                snippets: read_usize(&mut reader)?,
+            }),
+            10 => {
+                let kind = read_u8(&mut reader)?;
+                let pattern = read_string(&mut reader, "pattern", limits.max_word)?;
+                let pattern = match kind {
+                    0 => TermPattern::Prefix(pattern),
+                    1 => TermPattern::Wildcard(pattern),
+                    2 => TermPattern::Regex(pattern),
+                    3 => TermPattern::Fuzzy {
+                        term: pattern,
+                        max_distance: read_usize(&mut reader)?,
+                    },
+                    _ => return Err(DecodeError::Malformed),
                 };
-
-                return Some(ret);
+                Ok(Self::PatternSearch { pattern })
             }
-            1 => {
-                let mut length_buffer = [0; 8];
-                let length_result = reader.read_exact(&mut length_buffer);
-                if length_result.is_err() {
-                    return None;
-                }
-                let length = usize::from_be_bytes(length_buffer);
-
-                let mut string_buffer = vec![0; length.into()];
-                let _read_result = reader.read_exact(&mut string_buffer).unwrap();
-
-                let ret = Self::Search {
-                    word: String::from_utf8(string_buffer).unwrap(),
+            11 => Ok(Self::SearchPage {
+                word: read_string(&mut reader, "word", limits.max_word)?,
+                limit: read_usize(&mut reader)?,
+                cursor: read_optional_usize(&mut reader)?,
+            }),
+            12 => {
+                let id = read_usize(&mut reader)?;
+                let range = match read_u8(&mut reader)? {
+                    0 => TextRange::All,
+                    1 => TextRange::Bytes {
+                        start: read_usize(&mut reader)?,
+                        end: read_usize(&mut reader)?,
+                    },
+                    2 => TextRange::Lines {
+                        first: read_usize(&mut reader)?,
+                        last: read_usize(&mut reader)?,
+                    },
+                    _ => return Err(DecodeError::Malformed),
                 };
-                return Some(ret);
+                Ok(Self::RetrieveStream { id, range })
             }
-            2 => {
-                let mut bytes = [0; 8];
-                let read_result = reader.read_exact(&mut bytes);
-                if read_result.is_err() {
-                    return None;
-                }
+            13 => Ok(Self::Stats),
+            _ => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+// Requests and responses are sent over a connection as frames: a 4-byte length, an 8-byte id
+// chosen by the client, and then the message itself, which is `length` bytes long. The server
+// answers each request with a response frame carrying the same id, so a client can send several
+// requests before reading any responses, and match the responses up even if they arrive in a
+// different order. The length lets a frame be read in full before it is decoded, and lets the
+// server refuse an oversized frame without reading it.
+impl Request {
+    // Convert the request into a frame with the given id. Fails if the request is too long for a
+    // frame's 4-byte length.
+    pub fn to_frame(&self, id: u64) -> io::Result<Vec<u8>> {
+        encode_frame(id, self.to_bytes())
+    }
 
-                let id = usize::from_be_bytes(bytes);
-                let ret = Self::Retrieve { id: id };
+    // Whether sending the request twice has the same effect, and gets the same response, as sending
+    // it once. Only these requests may be resent when it isn't known whether the server got them.
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Request::Publish { .. } | Request::Delete { .. })
+    }
 
-                return Some(ret);
-            }
-            _ => return None,
+    // Read a frame from `reader` and return its id and request, using the default limits. If the
+    // frame is invalid, return `None`.
+    pub fn from_frame<R: Read>(mut reader: R) -> Option<(u64, Self)> {
+        let limits = Limits::default();
+        let (id, payload) = read_frame(&mut reader, limits.max_frame).ok()?;
+        Some((id, Self::decode_payload(&payload, &limits).ok()?))
+    }
+
+    // Decode the message of a frame read with `read_frame`. The message must take up the whole
+    // payload.
+    pub fn decode_payload(payload: &[u8], limits: &Limits) -> Result<Self, DecodeError> {
+        let mut reader = payload;
+        let request = Self::decode(&mut reader, limits)?;
+        if !reader.is_empty() {
+            return Err(DecodeError::TrailingBytes(reader.len()));
         }
+        Ok(request)
     }
 }
 
@@ -103,13 +495,153 @@ impl Request {
 pub enum Response {
     /// The document was successfully added to the archive with the given index
     PublishSuccess(usize),
-    /// The search for the word was successful, and the indices of the documents containing the
-    /// word are returned
+    /// The search for the word or phrase was successful, and the indices of the documents
+    /// containing it are returned
     SearchSuccess(Vec<usize>),
     /// The retrieval of the document was successful, and the document is returned
     RetrieveSuccess(String),
-    /// The request failed
-    Failure,
+    /// The request failed for the given reason
+    Error(ErrorResponse),
+    /// The frequency query was successful, and the per-document counts are returned
+    FrequencySuccess(Vec<Frequency>),
+    /// The retrieval of the metadata was successful, and the metadata is returned
+    MetadataSuccess(Metadata),
+    /// The timeline query was successful, and the per-year frequencies are returned
+    TimelineSuccess(Vec<TimelinePoint>),
+    /// The document was successfully deleted
+    DeleteSuccess,
+    /// The ranked search was successful, and the best matching documents are returned, most
+    /// relevant first
+    RankedSearchSuccess(Vec<ScoredDocument>),
+    /// The concordance search was successful, and the matching documents are returned with
+    /// snippets of the word or phrase in context
+    ConcordanceSuccess(Vec<Hit>),
+    /// The search found no documents, and these similarly spelled terms are suggested instead,
+    /// closest first
+    NoMatches(Vec<String>),
+    /// The paginated search was successful, and one page of the matching documents is returned
+    SearchPageSuccess(SearchPage),
+    /// The next piece of a document requested with `Request::RetrieveStream`, and whether it is
+    /// the last one
+    Chunk { data: Vec<u8>, last: bool },
+    /// The server's current load
+    StatsSuccess(ServerStats),
+}
+
+/// How busy a server is, as reported in answer to `Request::Stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /// The number of threads currently processing requests
+    pub workers: usize,
+    /// The number of requests waiting for a thread
+    pub queue_depth: usize,
+    /// The number of requests that have panicked since the server started
+    pub panics: usize,
+}
+
+/// Why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request could not be decoded
+    InvalidRequest,
+    /// The request, or part of it, is larger than the server accepts
+    TooLarge,
+    /// No document with the requested id exists
+    NotFound,
+    /// The requested document has been deleted
+    Deleted,
+    /// The server could not read or write its data directory
+    Storage,
+    /// The server is too busy to take the request
+    Overloaded,
+    /// The server failed in an unexpected way
+    Internal,
+    /// The query could not be parsed
+    InvalidQuery,
+    /// An error the client doesn't know about, sent by a newer server
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    // The number that identifies the code on the wire. These numbers never change meaning.
+    pub fn to_u16(self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 1,
+            ErrorCode::TooLarge => 2,
+            ErrorCode::NotFound => 3,
+            ErrorCode::Deleted => 4,
+            ErrorCode::Storage => 5,
+            ErrorCode::Overloaded => 6,
+            ErrorCode::Internal => 7,
+            ErrorCode::InvalidQuery => 8,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::InvalidRequest,
+            2 => ErrorCode::TooLarge,
+            3 => ErrorCode::NotFound,
+            4 => ErrorCode::Deleted,
+            5 => ErrorCode::Storage,
+            6 => ErrorCode::Overloaded,
+            7 => ErrorCode::Internal,
+            8 => ErrorCode::InvalidQuery,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+/// A failed request, as reported by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    /// What kind of failure this is
+    pub code: ErrorCode,
+    /// A description of the failure for people to read
+    pub message: String,
+    /// Whether the same request may succeed if it is sent again later
+    pub retryable: bool,
+}
+
+impl ErrorResponse {
+    // Create an error response with the given code and message. Overload and storage failures are
+    // assumed to be temporary, so they are retryable; everything else will fail the same way again.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorResponse {
+            code,
+            message: message.into(),
+            retryable: matches!(code, ErrorCode::Overloaded | ErrorCode::Storage),
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error {})", self.message, self.code.to_u16())
+    }
+}
+
+impl From<&DatabaseError> for ErrorResponse {
+    fn from(err: &DatabaseError) -> Self {
+        let code = match err {
+            DatabaseError::Io(_) => ErrorCode::Storage,
+            DatabaseError::NotFound(_) => ErrorCode::NotFound,
+            DatabaseError::Deleted(_) => ErrorCode::Deleted,
+            DatabaseError::Pattern(_) => ErrorCode::InvalidQuery,
+        };
+        ErrorResponse::new(code, err.to_string())
+    }
+}
+
+impl From<&DecodeError> for ErrorResponse {
+    fn from(err: &DecodeError) -> Self {
+        let code = match err {
+            DecodeError::TooLarge { .. } => ErrorCode::TooLarge,
+            _ => ErrorCode::InvalidRequest,
+        };
+        ErrorResponse::new(code, err.to_string())
+    }
 }
 impl Response {
     // TODO:
@@ -119,32 +651,139 @@ impl Response {
         match self {
             Self::PublishSuccess(index) => {
                 let mut bytes = vec![0];
-                bytes.extend(index.to_be_bytes().iter());
-                return bytes;
+                bytes.extend((*index as u64).to_be_bytes().iter());
+                bytes
             }
             Self::SearchSuccess(indices) => {
                 let mut bytes = vec![1];
-                bytes.extend(indices.len().to_be_bytes().iter());
+                bytes.extend((indices.len() as u64).to_be_bytes().iter());
                 for index in indices {
-                    bytes.extend(index.to_be_bytes().iter());
+                    bytes.extend((*index as u64).to_be_bytes().iter());
                 }
 
-                return bytes;
+                bytes
             }
             Self::RetrieveSuccess(doc) => {
                 let mut bytes = vec![2];
                 let length = doc.len();
-                bytes.extend(length.to_be_bytes().iter());
+                bytes.extend((length as u64).to_be_bytes().iter());
                 bytes.extend(doc.as_bytes());
-                return bytes;
+                bytes
+            }
+            Self::Error(error) => {
+                let mut bytes = vec![3];
+                bytes.extend(error.code.to_u16().to_be_bytes().iter());
+                bytes.push(error.retryable as u8);
+                bytes.extend((error.message.len() as u64).to_be_bytes().iter());
+                bytes.extend(error.message.as_bytes());
+                bytes
+            }
+            Self::FrequencySuccess(frequencies) => {
+                let mut bytes = vec![4];
+                bytes.extend((frequencies.len() as u64).to_be_bytes().iter());
+                for frequency in frequencies {
+                    bytes.extend((frequency.id as u64).to_be_bytes().iter());
+                    bytes.extend((frequency.count as u64).to_be_bytes().iter());
+                    bytes.extend(frequency.relative.to_bits().to_be_bytes().iter());
+                }
+
+                bytes
+            }
+            Self::MetadataSuccess(metadata) => {
+                let mut bytes = vec![5];
+                write_optional_string(&mut bytes, &metadata.title);
+                write_optional_string(&mut bytes, &metadata.author);
+                match metadata.year {
+                    Some(year) => {
+                        bytes.push(1);
+                        bytes.extend(year.to_be_bytes().iter());
+                    }
+                    None => bytes.push(0),
+                }
+                bytes
+            }
+            Self::TimelineSuccess(points) => {
+                let mut bytes = vec![6];
+                bytes.extend((points.len() as u64).to_be_bytes().iter());
+                for point in points {
+                    bytes.extend(point.year.to_be_bytes().iter());
+                    bytes.extend((point.count as u64).to_be_bytes().iter());
+                    bytes.extend((point.word_count as u64).to_be_bytes().iter());
+                    bytes.extend(point.relative.to_bits().to_be_bytes().iter());
+                }
+
+                bytes
+            }
+            Self::DeleteSuccess => vec![7],
+            Self::RankedSearchSuccess(documents) => {
+                let mut bytes = vec![8];
+                bytes.extend((documents.len() as u64).to_be_bytes().iter());
+                for document in documents {
+                    bytes.extend((document.id as u64).to_be_bytes().iter());
+                    bytes.extend(document.score.to_bits().to_be_bytes().iter());
+                }
+
+                bytes
+            }
+            Self::ConcordanceSuccess(hits) => {
+                let mut bytes = vec![9];
+                bytes.extend((hits.len() as u64).to_be_bytes().iter());
+                for hit in hits {
+                    bytes.extend((hit.id as u64).to_be_bytes().iter());
+                    bytes.extend((hit.count as u64).to_be_bytes().iter());
+                    bytes.extend((hit.snippets.len() as u64).to_be_bytes().iter());
+                    for snippet in &hit.snippets {
+                        bytes.extend((snippet.start as u64).to_be_bytes().iter());
+                        bytes.extend((snippet.end as u64).to_be_bytes().iter());
+                        for text in [&snippet.before, &snippet.matched, &snippet.after] {
+                            bytes.extend((text.len() as u64).to_be_bytes().iter());
+                            bytes.extend(text.as_bytes());
+                        }
+                    }
+                }
+
+                bytes
+            }
+            Self::NoMatches(suggestions) => {
+                let mut bytes = vec![10];
+                bytes.extend((suggestions.len() as u64).to_be_bytes().iter());
+                for suggestion in suggestions {
+                    bytes.extend((suggestion.len() as u64).to_be_bytes().iter());
+                    bytes.extend(suggestion.as_bytes());
+                }
+
+                bytes
+            }
+            Self::SearchPageSuccess(page) => {
+                let mut bytes = vec![11];
+                bytes.extend((page.ids.len() as u64).to_be_bytes().iter());
+                for id in &page.ids {
+                    bytes.extend((*id as u64).to_be_bytes().iter());
+                }
+                bytes.extend((page.total as u64).to_be_bytes().iter());
+                write_optional_usize(&mut bytes, page.next);
+                bytes
+            }
+            Self::Chunk { data, last } => {
+                let mut bytes = vec![12];
+                bytes.push(*last as u8);
+                bytes.extend((data.len() as u64).to_be_bytes().iter());
+                bytes.extend(data);
+                bytes
+            }
+            Self::StatsSuccess(stats) => {
+                let mut bytes = vec![13];
+                bytes.extend((stats.workers as u64).to_be_bytes().iter());
+                bytes.extend((stats.queue_depth as u64).to_be_bytes().iter());
+                bytes.extend((stats.panics as u64).to_be_bytes().iter());
+                bytes
             }
-            Self::Failure => return vec![3],
         }
     }
     // TODO:
     // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
     // `from_bytes` should return the original request. If the request is invalid, return `None`.
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
+    pub fn from_bytes<R: Read>(mut reader: R) -> Option<Self> {
         let mut response_type = [0; 1];
         let result = reader.read_exact(&mut response_type);
         if result.is_err() {
@@ -159,10 +798,10 @@ impl Response {
                     return None;
                 }
 
-                let id = usize::from_be_bytes(bytes);
+                let id = usize::try_from(u64::from_be_bytes(bytes)).ok()?;
                 let ret = Self::PublishSuccess(id);
 
-                return Some(ret);
+                Some(ret)
             }
             1 => {
                 let mut length_buffer = [0; 8];
@@ -170,7 +809,7 @@ impl Response {
                 if length_result.is_err() {
                     return None;
                 }
-                let length = usize::from_be_bytes(length_buffer);
+                let length = usize::try_from(u64::from_be_bytes(length_buffer)).ok()?;
 
                 let mut ret_vec: Vec<usize> = Vec::new();
                 for _ in 0..length {
@@ -180,12 +819,12 @@ impl Response {
                         return None;
                     }
 
-                    let id = usize::from_be_bytes(bytes);
+                    let id = usize::try_from(u64::from_be_bytes(bytes)).ok()?;
                     ret_vec.push(id);
                 }
 
                 let ret = Self::SearchSuccess(ret_vec);
-                return Some(ret);
+                Some(ret)
             }
             2 => {
                 let mut length_buffer = [0; 8];
@@ -193,20 +832,398 @@ impl Response {
                 if length_result.is_err() {
                     return None;
                 }
-                let length = usize::from_be_bytes(length_buffer);
+                let length = usize::try_from(u64::from_be_bytes(length_buffer)).ok()?;
+
+                let mut string_buffer = Vec::new();
+                let read_result = (&mut reader)
+                    .take(length as u64)
+                    .read_to_end(&mut string_buffer);
+                if read_result.is_err() || string_buffer.len() != length {
+                    return None;
+                }
+
+                let ret = Self::RetrieveSuccess(String::from_utf8(string_buffer).ok()?);
+
+                Some(ret)
+            }
+            3 => {
+                let mut bytes = [0; 3];
+                let read_result = reader.read_exact(&mut bytes);
+                if read_result.is_err() {
+                    return None;
+                }
+                let retryable = match bytes[2] {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+
+                let ret = Self::Error(ErrorResponse {
+                    code: ErrorCode::from_u16(u16::from_be_bytes([bytes[0], bytes[1]])),
+                    message: read_string(&mut reader, "message", usize::MAX).ok()?,
+                    retryable,
+                });
+                Some(ret)
+            }
+            4 => {
+                let mut length_buffer = [0; 8];
+                let length_result = reader.read_exact(&mut length_buffer);
+                if length_result.is_err() {
+                    return None;
+                }
+                let length = usize::try_from(u64::from_be_bytes(length_buffer)).ok()?;
+
+                let mut ret_vec: Vec<Frequency> = Vec::new();
+                for _ in 0..length {
+                    let mut bytes = [0; 24];
+                    let read_result = reader.read_exact(&mut bytes);
+                    if read_result.is_err() {
+                        return None;
+                    }
+
+                    ret_vec.push(Frequency {
+                        id: usize::try_from(u64::from_be_bytes(bytes[0..8].try_into().unwrap()))
+                            .ok()?,
+                        count: usize::try_from(u64::from_be_bytes(
+                            bytes[8..16].try_into().unwrap(),
+                        ))
+                        .ok()?,
+                        relative: f64::from_bits(u64::from_be_bytes(
+                            bytes[16..24].try_into().unwrap(),
+                        )),
+                    });
+                }
+
+                let ret = Self::FrequencySuccess(ret_vec);
+                Some(ret)
+            }
+            5 => {
+                let title = read_optional_string(&mut reader)?;
+                let author = read_optional_string(&mut reader)?;
 
-                let mut string_buffer = vec![0; length.into()];
-                let read_result = reader.read_exact(&mut string_buffer);
+                let mut flag = [0; 1];
+                let read_result = reader.read_exact(&mut flag);
                 if read_result.is_err() {
                     return None;
                 }
+                let year = match flag[0] {
+                    0 => None,
+                    1 => {
+                        let mut bytes = [0; 4];
+                        let read_result = reader.read_exact(&mut bytes);
+                        if read_result.is_err() {
+                            return None;
+                        }
+                        Some(i32::from_be_bytes(bytes))
+                    }
+                    _ => return None,
+                };
 
-                let ret = Self::RetrieveSuccess(String::from_utf8(string_buffer).unwrap());
+                let ret = Self::MetadataSuccess(Metadata {
+                    title,
+                    author,
+                    year,
+                });
+                Some(ret)
+            }
+            6 => {
+                let mut length_buffer = [0; 8];
+                let length_result = reader.read_exact(&mut length_buffer);
+                if length_result.is_err() {
+                    return None;
+                }
+                let length = usize::try_from(u64::from_be_bytes(length_buffer)).ok()?;
 
-                return Some(ret);
+                let mut ret_vec: Vec<TimelinePoint> = Vec::new();
+                for _ in 0..length {
+                    let mut bytes = [0; 28];
+                    let read_result = reader.read_exact(&mut bytes);
+                    if read_result.is_err() {
+                        return None;
+                    }
+
+                    ret_vec.push(TimelinePoint {
+                        year: i32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+                        count: usize::try_from(u64::from_be_bytes(
+                            bytes[4..12].try_into().unwrap(),
+                        ))
+                        .ok()?,
+                        word_count: usize::try_from(u64::from_be_bytes(
+                            bytes[12..20].try_into().unwrap(),
+                        ))
+                        .ok()?,
+                        relative: f64::from_bits(u64::from_be_bytes(
+                            bytes[20..28].try_into().unwrap(),
+                        )),
+                    });
+                }
+
+                let ret = Self::TimelineSuccess(ret_vec);
+                Some(ret)
             }
-            3 => return Some(Self::Failure),
-            _ => return None,
-        };
+            7 => Some(Self::DeleteSuccess),
+            8 => {
+                let length = read_usize(&mut reader).ok()?;
+
+                let mut ret_vec: Vec<ScoredDocument> = Vec::new();
+                for _ in 0..length {
+                    ret_vec.push(ScoredDocument {
+                        id: read_usize(&mut reader).ok()?,
+                        score: read_f64(&mut reader).ok()?,
+                    });
+                }
+
+                let ret = Self::RankedSearchSuccess(ret_vec);
+                Some(ret)
+            }
+            9 => {
+                let length = read_usize(&mut reader).ok()?;
+
+                let mut ret_vec: Vec<Hit> = Vec::new();
+                for _ in 0..length {
+                    let id = read_usize(&mut reader).ok()?;
+                    let count = read_usize(&mut reader).ok()?;
+                    let snippet_count = read_usize(&mut reader).ok()?;
+                    let mut snippets = Vec::new();
+                    for _ in 0..snippet_count {
+                        snippets.push(Snippet {
+                            start: read_usize(&mut reader).ok()?,
+                            end: read_usize(&mut reader).ok()?,
+                            before: read_string(&mut reader, "snippet", usize::MAX).ok()?,
+                            matched: read_string(&mut reader, "snippet", usize::MAX).ok()?,
+                            after: read_string(&mut reader, "snippet", usize::MAX).ok()?,
+                        });
+                    }
+                    ret_vec.push(Hit {
+                        id,
+                        count,
+                        snippets,
+                    });
+                }
+
+                let ret = Self::ConcordanceSuccess(ret_vec);
+                Some(ret)
+            }
+            10 => {
+                let length = read_usize(&mut reader).ok()?;
+
+                let mut ret_vec: Vec<String> = Vec::new();
+                for _ in 0..length {
+                    ret_vec.push(read_string(&mut reader, "suggestion", usize::MAX).ok()?);
+                }
+
+                let ret = Self::NoMatches(ret_vec);
+                Some(ret)
+            }
+            11 => {
+                let length = read_usize(&mut reader).ok()?;
+
+                let mut ids: Vec<usize> = Vec::new();
+                for _ in 0..length {
+                    ids.push(read_usize(&mut reader).ok()?);
+                }
+                let total = read_usize(&mut reader).ok()?;
+                let next = read_optional_usize(&mut reader).ok()?;
+
+                let ret = Self::SearchPageSuccess(SearchPage { ids, total, next });
+                Some(ret)
+            }
+            12 => {
+                let last = match read_u8(&mut reader).ok()? {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                let length = read_usize(&mut reader).ok()?;
+
+                let mut data = Vec::new();
+                let read_result = (&mut reader).take(length as u64).read_to_end(&mut data);
+                if read_result.is_err() || data.len() != length {
+                    return None;
+                }
+
+                let ret = Self::Chunk { data, last };
+                Some(ret)
+            }
+            13 => {
+                let workers = read_usize(&mut reader).ok()?;
+                let queue_depth = read_usize(&mut reader).ok()?;
+                let panics = read_usize(&mut reader).ok()?;
+
+                let ret = Self::StatsSuccess(ServerStats {
+                    workers,
+                    queue_depth,
+                    panics,
+                });
+                Some(ret)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Response {
+    // Convert the response into a frame with the given id, which should be the id of the request
+    // it answers. Fails if the response is too long for a frame's 4-byte length.
+    pub fn to_frame(&self, id: u64) -> io::Result<Vec<u8>> {
+        encode_frame(id, self.to_bytes())
+    }
+
+    // Read a frame from `reader` and return its id and response. Responses come from the server,
+    // which is trusted, so the frame is not size limited.
+    pub fn from_frame<R: Read>(mut reader: R) -> Result<(u64, Self), DecodeError> {
+        let (id, payload) = read_frame(&mut reader, usize::MAX)?;
+        let mut payload = &payload[..];
+        let response = Self::from_bytes(&mut payload).ok_or(DecodeError::Malformed)?;
+        if !payload.is_empty() {
+            return Err(DecodeError::TrailingBytes(payload.len()));
+        }
+        Ok((id, response))
+    }
+}
+
+// Frame the encoded message `payload` with its length and the given id.
+fn encode_frame(id: u64, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+    let length = u32::try_from(payload.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "message is {} bytes long, but a frame holds at most {} bytes",
+                payload.len(),
+                u32::MAX
+            ),
+        )
+    })?;
+    let mut bytes = length.to_be_bytes().to_vec();
+    bytes.extend(id.to_be_bytes().iter());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+// Read a frame from `reader` and return its id and undecoded message. The frame is refused
+// without reading its message if the message is longer than `max_frame` bytes.
+pub fn read_frame<R: Read>(
+    reader: &mut R,
+    max_frame: usize,
+) -> Result<(u64, Vec<u8>), DecodeError> {
+    let (id, length) = read_frame_header(reader)?;
+    check_frame_length(length, max_frame)?;
+    Ok((id, read_frame_payload(reader, length)?))
+}
+
+// Read the header of a frame from `reader` and return its id and the length of its message.
+pub fn read_frame_header<R: Read>(reader: &mut R) -> Result<(u64, usize), DecodeError> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    let length = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let id = u64::from_be_bytes(header[4..12].try_into().unwrap());
+    Ok((id, length as usize))
+}
+
+// Check the message length from a frame header against the largest allowed frame.
+pub fn check_frame_length(length: usize, max_frame: usize) -> Result<(), DecodeError> {
+    if length > max_frame {
+        return Err(DecodeError::TooLarge {
+            field: "frame",
+            length: length as u64,
+            limit: max_frame,
+        });
+    }
+    Ok(())
+}
+
+// Read the `length`-byte message that follows a frame header.
+pub fn read_frame_payload<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut payload = Vec::new();
+    reader.take(length as u64).read_to_end(&mut payload)?;
+    if payload.len() != length {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(payload)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, DecodeError> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Result<usize, DecodeError> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    usize::try_from(u64::from_be_bytes(bytes)).map_err(|_| DecodeError::Malformed)
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> Result<f64, DecodeError> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_bits(u64::from_be_bytes(bytes)))
+}
+
+// Read a length-prefixed UTF-8 string of at most `limit` bytes. `field` names the string in the
+// error if it is too long. The string is read incrementally rather than into a buffer of the
+// claimed length, so a bogus length can't cause a huge allocation.
+fn read_string<R: Read>(
+    reader: &mut R,
+    field: &'static str,
+    limit: usize,
+) -> Result<String, DecodeError> {
+    let length = read_usize(reader)?;
+    if length > limit {
+        return Err(DecodeError::TooLarge {
+            field,
+            length: length as u64,
+            limit,
+        });
+    }
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(DecodeError::Truncated);
+    }
+    String::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+// Write an optional string as a presence flag, followed by its length and bytes if present.
+fn write_optional_string(bytes: &mut Vec<u8>, value: &Option<String>) {
+    match value {
+        Some(value) => {
+            bytes.push(1);
+            bytes.extend((value.len() as u64).to_be_bytes().iter());
+            bytes.extend(value.as_bytes());
+        }
+        None => bytes.push(0),
+    }
+}
+
+// Read an optional string written by `write_optional_string`. The outer `Option` is `None` if
+// the bytes are invalid.
+fn read_optional_string<R: Read>(reader: &mut R) -> Option<Option<String>> {
+    let mut flag = [0; 1];
+    reader.read_exact(&mut flag).ok()?;
+    match flag[0] {
+        0 => Some(None),
+        1 => Some(Some(read_string(reader, "string", usize::MAX).ok()?)),
+        _ => None,
+    }
+}
+
+// Write an optional integer as a presence flag, followed by the integer if present.
+fn write_optional_usize(bytes: &mut Vec<u8>, value: Option<usize>) {
+    match value {
+        Some(value) => {
+            bytes.push(1);
+            bytes.extend((value as u64).to_be_bytes().iter());
+        }
+        None => bytes.push(0),
+    }
+}
+
+// Read an optional integer written by `write_optional_usize`.
+fn read_optional_usize<R: Read>(reader: &mut R) -> Result<Option<usize>, DecodeError> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        1 => Ok(Some(read_usize(reader)?)),
+        _ => Err(DecodeError::Malformed),
     }
 }
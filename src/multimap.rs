@@ -1,25 +1,237 @@
 use std::borrow::Borrow;
 use std::collections::{hash_map::DefaultHasher, LinkedList};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLock;
 
+/// The average number of entries per bucket above which the map grows
+const MAX_LOAD_FACTOR: usize = 4;
+/// The number of buckets each write moves to the new table while the map is growing
+const MIGRATION_STEP: usize = 2;
+
 // The ConcurrentMultiMap struct is a concurrent hash map that allows multiple values to be
 // associated with a single key. It is implemented using a vector of RwLocks, where each lock
 // protects a linked list of key-value pairs.
+//
+// When the average number of entries per bucket exceeds `MAX_LOAD_FACTOR`, the map grows by
+// allocating a second table with twice as many buckets. Rather than rehashing everything at once,
+// each subsequent `set` moves a few buckets from the current table to the new one, so no
+// operation ever waits for a whole-table rehash. While the move is in progress, a bucket of the
+// current table that has already been moved is marked as such, and operations on its keys go to
+// the new table instead. Once every bucket has been moved, the new table replaces the current one.
+//
+// The tables themselves are behind an outer RwLock. Ordinary operations only take it for reading,
+// so they never block each other; it is only taken for writing to briefly install or retire a
+// table. A bucket of the current table is always locked before a bucket of the new table, and no
+// operation holds more than one bucket of the same table at once, so the bucket locks can't
+// deadlock.
 pub struct ConcurrentMultiMap<K: Hash + Eq, V> {
-    buckets: Vec<RwLock<LinkedList<(K, V)>>>,
+    tables: RwLock<Tables<K, V>>,
+    /// The number of key-value pairs in the map
+    len: AtomicUsize,
+}
+
+struct Tables<K, V> {
+    /// The table that all keys live in, except for those in buckets that have been moved
+    current: Vec<RwLock<Bucket<K, V>>>,
+    /// The table that buckets are being moved to, if the map is growing
+    next: Option<Vec<RwLock<Bucket<K, V>>>>,
+    /// The index of the next bucket of `current` to be moved
+    claimed: AtomicUsize,
+    /// The number of buckets of `current` that have finished moving
+    moved: AtomicUsize,
+}
+
+struct Bucket<K, V> {
+    entries: LinkedList<(K, V)>,
+    /// Whether the entries have been moved to the next table
+    moved: bool,
+}
+
+impl<K, V> Bucket<K, V> {
+    fn new() -> Self {
+        Bucket {
+            entries: LinkedList::new(),
+            moved: false,
+        }
+    }
+}
+
+fn new_table<K, V>(bucket_count: usize) -> Vec<RwLock<Bucket<K, V>>> {
+    (0..bucket_count)
+        .map(|_| RwLock::new(Bucket::new()))
+        .collect()
+}
+
+// Remove the entries for which `keep` returns false from the list, and return them.
+fn filter_entries<K, V, F>(entries: &mut LinkedList<(K, V)>, mut keep: F) -> Vec<(K, V)>
+where
+    F: FnMut(&K, &V) -> bool,
+{
+    let mut removed = Vec::new();
+    for (k, v) in std::mem::take(entries) {
+        if keep(&k, &v) {
+            entries.push_back((k, v));
+        } else {
+            removed.push((k, v));
+        }
+    }
+    removed
+}
+
+fn hash_of<Q: Hash + ?Sized>(key: &Q) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
 }
 
 impl<K: Hash + Eq, V> ConcurrentMultiMap<K, V> {
-    // Create a new empty ConcurrentMultiMap with the given number of buckets.
+    // Create a new empty ConcurrentMultiMap with the given initial number of buckets.
     pub fn new(bucket_count: usize) -> Self {
-        let mut ret = ConcurrentMultiMap {
-            buckets: Vec::new(),
+        ConcurrentMultiMap {
+            tables: RwLock::new(Tables {
+                current: new_table(bucket_count.max(1)),
+                next: None,
+                claimed: AtomicUsize::new(0),
+                moved: AtomicUsize::new(0),
+            }),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    // The number of key-value pairs in the map
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // Whether the map contains no key-value pairs
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The number of buckets in the map. While the map is growing, this is the size of the new
+    // table.
+    pub fn bucket_count(&self) -> usize {
+        let tables = self.tables.read().unwrap();
+        match &tables.next {
+            Some(next) => next.len(),
+            None => tables.current.len(),
+        }
+    }
+
+    // Remove the given key and return all the values that were associated with it.
+    pub fn remove_all<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write_bucket(hash_of(key), |entries| {
+            let removed = filter_entries(entries, |k, _| k.borrow() != key);
+            self.len.fetch_sub(removed.len(), Ordering::SeqCst);
+            removed.into_iter().map(|(_, v)| v).collect()
+        })
+    }
+
+    // Keep only the key-value pairs for which `predicate` returns true. Each bucket is locked for
+    // writing in turn, so the map stays available while this runs. Pairs that are inserted
+    // concurrently may or may not be visited. While the map is growing, a pair may be visited
+    // twice (once in each table), so `predicate` should give the same answer for the same pair.
+    pub fn retain<F>(&self, mut predicate: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let tables = self.tables.read().unwrap();
+        let next = tables.next.iter().flatten();
+        for bucket in tables.current.iter().chain(next) {
+            let mut bucket = bucket.write().unwrap();
+            if bucket.moved {
+                continue;
+            }
+            let removed = filter_entries(&mut bucket.entries, &mut predicate);
+            self.len.fetch_sub(removed.len(), Ordering::SeqCst);
+        }
+    }
+
+    // Run `f` on the entries of the bucket that holds keys with the given hash, with the bucket
+    // locked for writing. This is the bucket of the current table, unless that bucket has already
+    // been moved to the new table. Every write also moves a few buckets along if the map is
+    // growing, and checks whether the map needs to start or finish growing afterwards.
+    fn write_bucket<F, R>(&self, hash: usize, f: F) -> R
+    where
+        F: FnOnce(&mut LinkedList<(K, V)>) -> R,
+    {
+        let (result, finished) = {
+            let tables = self.tables.read().unwrap();
+            let finished = self.migrate(&tables);
+
+            let mut bucket = tables.current[hash % tables.current.len()].write().unwrap();
+            let result = if bucket.moved {
+                drop(bucket);
+                let next = tables.next.as_ref().unwrap();
+                let mut bucket = next[hash % next.len()].write().unwrap();
+                f(&mut bucket.entries)
+            } else {
+                f(&mut bucket.entries)
+            };
+            (result, finished)
+        };
+        self.maybe_resize(finished);
+        result
+    }
+
+    // Move up to `MIGRATION_STEP` buckets from the current table to the next one, if the map is
+    // growing. Each bucket is claimed with an atomic counter so that concurrent writers move
+    // different buckets. Returns whether every bucket has now been moved.
+    fn migrate(&self, tables: &Tables<K, V>) -> bool {
+        let next = match &tables.next {
+            Some(next) => next,
+            None => return false,
         };
-        for _ in 0..bucket_count {
-            ret.buckets.push(RwLock::new(LinkedList::new()));
+        for _ in 0..MIGRATION_STEP {
+            let index = tables.claimed.fetch_add(1, Ordering::SeqCst);
+            if index >= tables.current.len() {
+                break;
+            }
+            let mut bucket = tables.current[index].write().unwrap();
+            while let Some((k, v)) = bucket.entries.pop_front() {
+                let target = hash_of(&k) % next.len();
+                next[target].write().unwrap().entries.push_back((k, v));
+            }
+            bucket.moved = true;
+            tables.moved.fetch_add(1, Ordering::SeqCst);
+        }
+        tables.moved.load(Ordering::SeqCst) == tables.current.len()
+    }
+
+    // Called after a write. If every bucket has been moved, replace the current table with the
+    // new one. Otherwise, if the map is over its load factor and not already growing, start
+    // growing it. The new table is allocated before taking the outer lock for writing, so the lock
+    // is only held long enough to swap it in.
+    fn maybe_resize(&self, finished: bool) {
+        if finished {
+            let mut tables = self.tables.write().unwrap();
+            if tables.next.is_some() && tables.moved.load(Ordering::SeqCst) == tables.current.len()
+            {
+                tables.current = tables.next.take().unwrap();
+            }
+            return;
+        }
+
+        let bucket_count = {
+            let tables = self.tables.read().unwrap();
+            if tables.next.is_some() || self.len() <= tables.current.len() * MAX_LOAD_FACTOR {
+                return;
+            }
+            tables.current.len()
+        };
+        let next = new_table(bucket_count * 2);
+
+        let mut tables = self.tables.write().unwrap();
+        if tables.next.is_none() && tables.current.len() == bucket_count {
+            tables.next = Some(next);
+            tables.claimed.store(0, Ordering::SeqCst);
+            tables.moved.store(0, Ordering::SeqCst);
         }
-        return ret;
     }
 }
 
@@ -28,49 +240,94 @@ impl<K: Hash + Eq, V: Clone + Eq> ConcurrentMultiMap<K, V> {
     // corresponding bucket in the vector by modulo-ing the hash by the number of buckets. Then,
     // take a writer lock of the bucket and iterate over the linked list, checking if the
     // key-values pair already exists. If it does, return early. Otherwise, add the key-value pair
-    // to the linked list.
+    // to the linked list. If the bucket has already been moved to the new table, do the same with
+    // the key's bucket in the new table instead.
     pub fn set(&self, key: K, value: V) {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        let index: usize = (hash as usize) % self.buckets.len();
-        let mut list = self.buckets[index].write().unwrap();
-        let iter = (*list).iter_mut();
-
-        for (k, v) in iter {
-            if *k == key && *v == value {
+        let hash = hash_of(&key);
+        self.write_bucket(hash, |entries| {
+            if entries.iter().any(|(k, v)| *k == key && *v == value) {
                 return;
             }
-        }
+            entries.push_back((key, value));
+            self.len.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    // Remove the given value from the given key. Returns whether the pair was in the map.
+    pub fn remove<Q>(&self, key: &Q, value: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write_bucket(hash_of(key), |entries| {
+            let removed = filter_entries(entries, |k, v| !(k.borrow() == key && v == value));
+            self.len.fetch_sub(removed.len(), Ordering::SeqCst);
+            !removed.is_empty()
+        })
+    }
 
-        list.push_back((key, value));
+    // Atomically read and modify all the values associated with `key`. The values are taken out
+    // of the map and passed to `f`, and whatever `f` leaves in the vector becomes the key's new set
+    // of values, with duplicates removed. The key's bucket stays locked for writing the whole time,
+    // so no other thread can observe the key in between. Returns the result of `f`.
+    pub fn update<F, R>(&self, key: K, f: F) -> R
+    where
+        K: Clone,
+        F: FnOnce(&mut Vec<V>) -> R,
+    {
+        let hash = hash_of(&key);
+        self.write_bucket(hash, |entries| {
+            let mut values: Vec<V> = filter_entries(entries, |k, _| *k != key)
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect();
+            let before = values.len();
+            let result = f(&mut values);
+
+            let mut after = 0;
+            for (i, value) in values.iter().enumerate() {
+                if !values[..i].contains(value) {
+                    entries.push_back((key.clone(), value.clone()));
+                    after += 1;
+                }
+            }
+            if after > before {
+                self.len.fetch_add(after - before, Ordering::SeqCst);
+            } else {
+                self.len.fetch_sub(before - after, Ordering::SeqCst);
+            }
+            result
+        })
     }
 
     // Retrieve all values associated with `key`. To do so, hash the key, and find the
     // corresponding bucket in the vector by modulo-ing the hash by the number of buckets. Then,
-    // take a reader lock of the bucker and iterate over the linked list, collecting all values
-    // associated with the key by `clone`-ing them. Return the collected values.
+    // take a reader lock of the bucket and iterate over the linked list, collecting all values
+    // associated with the key by `clone`-ing them. If the bucket has already been moved to the new
+    // table, read the key's bucket in the new table instead. Return the collected values.
     pub fn get<Q>(&self, key: &Q) -> Vec<V>
     where
         K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        let index: usize = (hash as usize) % self.buckets.len();
-        let list = self.buckets[index].read().unwrap();
-        let iter = (*list).iter();
-        let mut ans: Vec<V> = Vec::new();
-
-        for (k, v) in iter {
-            if k.borrow() == key {
-                ans.push((*v).clone());
-            }
+        let hash = hash_of(key);
+        let tables = self.tables.read().unwrap();
+
+        let list = tables.current[hash % tables.current.len()].read().unwrap();
+        let collect = |entries: &LinkedList<(K, V)>| {
+            entries
+                .iter()
+                .filter(|(k, _)| k.borrow() == key)
+                .map(|(_, v)| v.clone())
+                .collect()
+        };
+        if !list.moved {
+            return collect(&list.entries);
         }
+        drop(list);
 
-        return ans;
+        let next = tables.next.as_ref().unwrap();
+        let list = next[hash % next.len()].read().unwrap();
+        collect(&list.entries)
     }
 }
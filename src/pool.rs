@@ -1,6 +1,15 @@
+use crossbeam_deque::{self as deque, Injector, Stealer};
+use crossbeam_utils::Backoff;
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    fmt, iter,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 // We represent a job as a boxed closure that can be sent across threads. Since the closure is
@@ -8,79 +17,575 @@ use std::{
 // it to other threads.
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// A function called with the id of a worker and the payload of a job that panicked on it
+pub type PanicHook = Arc<dyn Fn(usize, &(dyn Any + Send)) + Send + Sync>;
+
+/// Options controlling the size of a `ThreadPool` and how it handles panics
+#[derive(Clone)]
+pub struct PoolConfig {
+    /// The number of worker threads kept running even when there is no work
+    pub min_workers: usize,
+    /// The most worker threads that may run at once. Workers beyond `min_workers` are spawned
+    /// when jobs are waiting with no idle worker to take them.
+    pub max_workers: usize,
+    /// How long a worker beyond `min_workers` may sit idle before it exits
+    pub keep_alive: Duration,
+    /// The most jobs that may wait in the queue for a free worker. Submitting a job to a full
+    /// queue blocks or fails, depending on the method used.
+    pub queue_capacity: usize,
+    /// Called on the worker's thread whenever a job started with `execute` panics
+    pub panic_hook: Option<PanicHook>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_workers: 4,
+            max_workers: 16,
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: 1024,
+            panic_hook: None,
+        }
+    }
+}
+
+impl fmt::Debug for PoolConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolConfig")
+            .field("min_workers", &self.min_workers)
+            .field("max_workers", &self.max_workers)
+            .field("keep_alive", &self.keep_alive)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("panic_hook", &self.panic_hook.as_ref().map(|_| "Fn"))
+            .finish()
+    }
+}
+
+// The message a panic was raised with, for logging. Panics raised with `panic!` carry either a
+// `&str` or a `String`; anything else is described generically.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
+
+/// The worker slots that are taken
+struct Slots {
+    /// The number of running workers
+    workers: usize,
+    /// The ids of the slots with no running worker
+    free: Vec<usize>,
+}
+
+// The queue of jobs shared between the pool and its workers. It holds at most `capacity` jobs.
+//
+// Rather than one queue that every worker locks, each worker has its own deque, and jobs submitted
+// to the pool go into a global injector queue. A worker takes jobs from its own deque first. When
+// that is empty, it moves a batch of jobs from the injector into its deque, and failing that it
+// steals a job from the deque of another worker. None of these take a lock, so workers only
+// contend with each other when they run out of jobs and go to sleep.
+//
+// Since the jobs are spread over several deques, `queued` counts them all, both to enforce the
+// capacity and to tell workers whether there is anything left to find. Workers with nothing to do
+// wait on `not_empty`, and submitters waiting for room wait on `not_full`. Both only take `lock`
+// when `sleeping` or `blocked` say that someone is waiting, and the waiting side checks `queued`
+// again after taking `lock` and announcing itself, so no wakeup is missed.
+//
+// The queue also decides when the pool grows and shrinks, since that depends on how many jobs
+// and idle workers there are. Each worker has a slot, with an id from 0 up to the most workers
+// the pool may have, and a worker only exists while its slot is taken. A slot's deque outlives
+// the threads that use it, so its stealer can be shared up front.
+struct JobQueue {
+    injector: Injector<Job>,
+    /// The deque of each slot, taken by the slot's worker while it runs
+    locals: Vec<Mutex<Option<deque::Worker<Job>>>>,
+    stealers: Vec<Stealer<Job>>,
+    /// The number of jobs in the injector and the deques
+    queued: AtomicUsize,
+    /// The number of workers waiting on `not_empty`
+    sleeping: AtomicUsize,
+    /// The number of submitters waiting on `not_full`
+    blocked: AtomicUsize,
+    closed: AtomicBool,
+    lock: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    slots: Mutex<Slots>,
+    /// The number of running workers, kept in step with `slots` for reading without the lock
+    workers: AtomicUsize,
+    capacity: usize,
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Duration,
+}
+
+impl JobQueue {
+    // Create an empty queue for a pool whose first `min_workers` slots are taken.
+    fn new(config: &PoolConfig) -> JobQueue {
+        let max_workers = config.max_workers.max(config.min_workers).max(1);
+        let locals: Vec<deque::Worker<Job>> = (0..max_workers)
+            .map(|_| deque::Worker::new_fifo())
+            .collect();
+        JobQueue {
+            injector: Injector::new(),
+            stealers: locals.iter().map(|local| local.stealer()).collect(),
+            locals: locals
+                .into_iter()
+                .map(|local| Mutex::new(Some(local)))
+                .collect(),
+            queued: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            slots: Mutex::new(Slots {
+                workers: config.min_workers,
+                free: (config.min_workers..max_workers).rev().collect(),
+            }),
+            workers: AtomicUsize::new(config.min_workers),
+            capacity: config.queue_capacity.max(1),
+            min_workers: config.min_workers,
+            max_workers,
+            keep_alive: config.keep_alive,
+        }
+    }
+
+    // Count one more job in the queue if there is room for it. The count is only ever raised while
+    // it is below the capacity, so it never overstates how full the queue is, even for a moment.
+    fn reserve(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.capacity).then_some(queued + 1)
+            })
+            .is_ok()
+    }
+
+    // Add `f` to the queue, waiting until `deadline` for room if the queue is full. Without a
+    // deadline this waits as long as it takes. The job is only boxed once there is room for it,
+    // so that it can be handed back unchanged if the deadline passes first.
+    //
+    // If more jobs are now waiting than there are idle workers to take them, and the pool isn't
+    // at its largest, a slot is taken for a new worker, and its id is returned so that the caller
+    // can spawn the worker.
+    fn push<F>(&self, f: F, deadline: Option<Instant>) -> Result<Option<usize>, F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.reserve() {
+            let mut guard = self.lock.lock().unwrap();
+            self.blocked.fetch_add(1, Ordering::SeqCst);
+            let reserved = loop {
+                if self.reserve() {
+                    break true;
+                }
+                guard = match deadline {
+                    None => self.not_full.wait(guard).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break false;
+                        }
+                        self.not_full.wait_timeout(guard, deadline - now).unwrap().0
+                    }
+                };
+            };
+            self.blocked.fetch_sub(1, Ordering::SeqCst);
+            if !reserved {
+                return Err(f);
+            }
+        }
+        self.injector.push(Box::new(f));
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.not_empty.notify_one();
+        }
+        Ok(self.grow())
+    }
+
+    // Take a free slot for a new worker if more jobs are waiting than there are idle workers to
+    // take them, and the pool isn't at its largest. Returns the slot's id so that the caller can
+    // spawn the worker.
+    fn grow(&self) -> Option<usize> {
+        if self.workers.load(Ordering::SeqCst) < self.max_workers
+            && self.queued.load(Ordering::SeqCst) > self.sleeping.load(Ordering::SeqCst)
+        {
+            let mut slots = self.slots.lock().unwrap();
+            if let Some(id) = slots.free.pop() {
+                slots.workers += 1;
+                self.workers.store(slots.workers, Ordering::SeqCst);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    // Find a job for the worker with the deque `local`: from the deque itself, then a batch from
+    // the injector, then from another worker's deque. Stealing can fail spuriously when it races
+    // with another thread, in which case it is tried again.
+    fn find(&self, local: &deque::Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector.steal_batch_and_pop(local).or_else(|| {
+                    self.stealers
+                        .iter()
+                        .map(|stealer| stealer.steal())
+                        .collect()
+                })
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })
+    }
+
+    // Take the next job for the worker with the deque `local`, waiting for one if there are none.
+    // Returns `None`, telling the worker to exit, once the queue has been closed and every job
+    // left in it has been taken, or once the worker has waited `keep_alive` for a job while the
+    // pool has more than `min_workers` workers. An exiting worker is no longer counted, but its
+    // slot is only freed by `release` once its deque has been put back.
+    //
+    // Waking a sleeping worker costs the submitter a system call, so a worker that runs out of
+    // jobs keeps looking for a moment before it sleeps. It backs off exponentially: a few short
+    // busy-waits catch a job submitted within a microsecond or so, then a few `yield_now`s give the
+    // CPU to a submitter that shares it, and after about ten rounds the worker goes to sleep.
+    fn pop(&self, local: &deque::Worker<Job>) -> Option<Job> {
+        loop {
+            let backoff = Backoff::new();
+            let job = loop {
+                match self.find(local) {
+                    Some(job) => break Some(job),
+                    None if backoff.is_completed() => break None,
+                    None => backoff.snooze(),
+                }
+            };
+            if let Some(job) = job {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                if self.blocked.load(Ordering::SeqCst) > 0 {
+                    let _guard = self.lock.lock().unwrap();
+                    self.not_full.notify_one();
+                }
+                return Some(job);
+            }
+
+            let guard = self.lock.lock().unwrap();
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            if self.queued.load(Ordering::SeqCst) > 0 {
+                // A job is on its way into the injector, or was missed while stealing
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                drop(guard);
+                thread::yield_now();
+                continue;
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                drop(guard);
+                self.retire(true);
+                return None;
+            }
+            let (guard, timeout) = self.not_empty.wait_timeout(guard, self.keep_alive).unwrap();
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            drop(guard);
+            if timeout.timed_out() && self.queued.load(Ordering::SeqCst) == 0 && self.retire(false)
+            {
+                return None;
+            }
+        }
+    }
+
+    // Stop counting a worker that is about to exit, unless that would leave fewer than
+    // `min_workers` and `force` is false.
+    fn retire(&self, force: bool) -> bool {
+        let mut slots = self.slots.lock().unwrap();
+        if !force && slots.workers <= self.min_workers {
+            return false;
+        }
+        slots.workers -= 1;
+        self.workers.store(slots.workers, Ordering::SeqCst);
+        true
+    }
+
+    // Take the deque of the slot `id` for a worker starting in it.
+    fn take_local(&self, id: usize) -> deque::Worker<Job> {
+        self.locals[id].lock().unwrap().take().unwrap()
+    }
+
+    // Put back the deque of the slot `id` when its worker exits. If the worker exited from `pop`
+    // rather than by panicking, the slot is also freed for a new worker.
+    //
+    // A job submitted while a worker retires may find the pool neither full of workers nor with a
+    // free slot, since the slot is only freed here, after the worker has stopped looking for jobs.
+    // So once the slot is freed, the queue is checked again, and the id of a slot for a new worker
+    // is returned if a job has been left without one.
+    fn release(&self, id: usize, local: deque::Worker<Job>, retired: bool) -> Option<usize> {
+        *self.locals[id].lock().unwrap() = Some(local);
+        if !retired {
+            return None;
+        }
+        self.slots.lock().unwrap().free.push(id);
+        self.grow()
+    }
+
+    // Stop accepting jobs and wake every waiting worker, so that they exit once the queue is empty.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = self.lock.lock().unwrap();
+        self.not_empty.notify_all();
+    }
+}
+
+// The result of a spawned job, filled in by the worker that runs it and waited for by its
+// `JobHandle`.
+struct JobResult<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    finished: Condvar,
+}
+
+/// A handle to a job started with `ThreadPool::spawn`, which can be used to wait for the job and
+/// collect its result. Dropping the handle lets the job run to completion without anyone waiting
+/// for it.
+pub struct JobHandle<T> {
+    shared: Arc<JobResult<T>>,
+}
+
+impl<T> JobHandle<T> {
+    // Wait for the job to finish and return its result, or the payload it panicked with, like
+    // `std::thread::JoinHandle::join`.
+    pub fn join(self) -> thread::Result<T> {
+        let mut result = self.shared.result.lock().unwrap();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            result = self.shared.finished.wait(result).unwrap();
+        }
+    }
+
+    // Return the job's result if it has finished, without waiting. If it hasn't, the handle is
+    // given back so that it can be joined later.
+    pub fn try_join(self) -> Result<thread::Result<T>, Self> {
+        let result = self.shared.result.lock().unwrap().take();
+        result.ok_or(self)
+    }
+
+    // Whether the job has finished, either by returning or by panicking.
+    pub fn is_finished(&self) -> bool {
+        self.shared.result.lock().unwrap().is_some()
+    }
+}
+
 struct Worker {
-    _id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-impl Worker {
-    // Spawn a new thread that will loop forever, receiving jobs from the receiver and executing
-    // them. If the `recv()` method returns an error, it means the thread pool has been dropped and
-    // the thread should exit by breaking the loop.
-    // This function should return a `Worker` as a handle to the thread.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        Worker {
-            _id: id,
-            thread: Some(thread::spawn(move || loop {
-                let guard = receiver.lock().unwrap();
-                let result = guard.recv();
-                match result {
-                    Ok(job) => {
-                        drop(guard);
-                        job();
+    /// The worker's current thread, which is replaced if the thread dies
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+    /// The number of jobs that have panicked on this worker
+    panics: AtomicUsize,
+}
+
+// The state shared between the pool and its worker threads
+struct PoolShared {
+    queue: JobQueue,
+    workers: Vec<Worker>,
+    panic_hook: Option<PanicHook>,
+}
+
+// Spawn the thread for the worker `id`, which will loop forever, taking jobs from the queue and
+// executing them. When the queue returns `None`, either the thread pool has been dropped or the
+// worker has been idle for too long, and the thread should exit by breaking the loop.
+//
+// Each job runs under `catch_unwind`, so a panicking job is counted and reported to the panic
+// hook, and the worker moves on to the next job. If the thread dies anyway, for instance because
+// the panic hook itself panicked, its `Sentinel` spawns a replacement, so the pool never shrinks.
+//
+// The worker's handle slot stays locked until the new thread's handle is stored in it, so that if
+// the new thread dies straight away, its replacement's handle is stored after this one.
+fn spawn_worker(shared: Arc<PoolShared>, id: usize) {
+    let mut slot = shared.workers[id].thread.lock().unwrap();
+    let handle = thread::spawn({
+        let shared = Arc::clone(&shared);
+        move || {
+            let mut sentinel = Sentinel {
+                shared: Arc::clone(&shared),
+                id,
+                local: Some(shared.queue.take_local(id)),
+                retired: false,
+            };
+            let local = sentinel.local.as_ref().unwrap();
+            while let Some(job) = shared.queue.pop(local) {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    shared.workers[id].panics.fetch_add(1, Ordering::SeqCst);
+                    if let Some(hook) = &shared.panic_hook {
+                        hook(id, &*payload);
                     }
-                    Err(_err) => return,
                 }
-            })),
+            }
+            sentinel.retired = true;
+        }
+    });
+    *slot = Some(handle);
+}
+
+// Dropped when a worker's thread exits, to put back the worker's deque. If the thread is exiting
+// because of a panic, a new thread takes over the worker and its deque. If it retired just as a
+// job arrived, a new worker is spawned for the job.
+struct Sentinel {
+    shared: Arc<PoolShared>,
+    id: usize,
+    local: Option<deque::Worker<Job>>,
+    /// Whether the worker was told to exit by the queue
+    retired: bool,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        let local = self.local.take().unwrap();
+        if let Some(id) = self.shared.queue.release(self.id, local, self.retired) {
+            spawn_worker(Arc::clone(&self.shared), id);
+        }
+        if thread::panicking() {
+            spawn_worker(Arc::clone(&self.shared), self.id);
         }
     }
 }
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<PoolShared>,
 }
 
 impl ThreadPool {
-    // Spawn `size` workers by calling the `Worker::new` function `size` times, each time with a
-    // unique id. You will need to create a channel and wrap the receiver in an `Arc<Mutex<...>>`
-    // in order to share it with the worker threads. Finally, return an instance of `ThreadPool`
-    // that has the workers and the sender.
+    // Spawn a pool of exactly `size` workers with the default queue capacity.
     pub fn new(size: usize) -> ThreadPool {
-        let (tx, rx) = mpsc::channel::<Job>();
-        let mut ret = ThreadPool {
-            workers: Vec::new(),
-            sender: Some(tx),
-        };
-        let receiver = Arc::new(Mutex::new(rx));
-        for i in 0..size {
-            ret.workers.push(Worker::new(i, Arc::clone(&receiver)));
+        ThreadPool::with_config(PoolConfig {
+            min_workers: size,
+            max_workers: size,
+            ..PoolConfig::default()
+        })
+    }
+
+    // Make a slot for each of the pool's possible workers, then spawn `config.min_workers` workers
+    // by calling `spawn_worker` once for each, each time with a unique id and a handle to the
+    // state shared with the pool. More workers are spawned as jobs are submitted.
+    pub fn with_config(config: PoolConfig) -> ThreadPool {
+        let queue = JobQueue::new(&config);
+        let workers = (0..queue.max_workers)
+            .map(|_| Worker {
+                thread: Mutex::new(None),
+                panics: AtomicUsize::new(0),
+            })
+            .collect();
+        let shared = Arc::new(PoolShared {
+            queue,
+            workers,
+            panic_hook: config.panic_hook,
+        });
+        for id in 0..config.min_workers {
+            spawn_worker(Arc::clone(&shared), id);
         }
+        ThreadPool { shared }
+    }
 
-        return ret;
+    // The number of running workers.
+    pub fn size(&self) -> usize {
+        self.shared.queue.workers.load(Ordering::SeqCst)
     }
 
-    // Send the job `f` to the worker threads via the channel `send` method.
+    // The number of jobs waiting for a worker.
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queue.queued.load(Ordering::SeqCst)
+    }
+
+    // Queue the job `f`, waiting until `deadline` for room as `JobQueue::push` does, and spawn a
+    // new worker if the queue asks for one.
+    fn submit<F>(&self, f: F, deadline: Option<Instant>) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(id) = self.shared.queue.push(f, deadline)? {
+            spawn_worker(Arc::clone(&self.shared), id);
+        }
+        Ok(())
+    }
+
+    // The number of jobs that have panicked on each worker, indexed by worker id, including the
+    // workers that aren't running at the moment. Panics in jobs started with `spawn` are handed
+    // to their `JobHandle` instead, and aren't counted.
+    pub fn panic_counts(&self) -> Vec<usize> {
+        self.shared
+            .workers
+            .iter()
+            .map(|worker| worker.panics.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    // Queue the job `f` for the workers, blocking until there is room in the queue.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let sender = self.sender.as_ref().unwrap();
-        let _ = (*sender).send(Box::new(f));
+        let _ = self.submit(f, None);
+    }
+
+    // Queue the job `f` for the workers if there is room in the queue right now. If the queue is
+    // full, the job is handed back without being run, so the caller can decide what to do with it.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(f, Some(Instant::now()))
+    }
+
+    // Queue the job `f` for the workers, waiting up to `timeout` for room in the queue. If the
+    // queue is still full after that, the job is handed back without being run.
+    pub fn execute_timeout<F>(&self, f: F, timeout: Duration) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(f, Some(Instant::now() + timeout))
+    }
+
+    // Queue the job `f` like `execute`, and return a handle for collecting what it returns. A
+    // panic in the job is caught and handed to the handle as well, rather than taking down the
+    // worker that ran it.
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(JobResult {
+            result: Mutex::new(None),
+            finished: Condvar::new(),
+        });
+        let job_shared = Arc::clone(&shared);
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            *job_shared.result.lock().unwrap() = Some(result);
+            job_shared.finished.notify_all();
+        });
+        JobHandle { shared }
     }
 }
 
 impl Drop for ThreadPool {
-    // First, take ownership of the sender from inside the option, then drop it. This will trigger
-    // the worker threads to stop since the channel is closed, so you should then call `join` on
-    // each worker thread handle to make sure they finish executing. Calling `join` will also
-    // require you to take ownership of the worker thread handle from inside the option.
+    // First, close the queue. This will trigger the worker threads to stop once they have run the
+    // jobs still in the queue, so we then call `join` on each worker thread handle to make sure
+    // they finish executing. A thread that dies stores its replacement's handle before it exits,
+    // so each worker's handle is taken again after joining until there is none left.
     fn drop(&mut self) {
-        let sender = self.sender.take().unwrap();
-        drop(sender);
+        self.shared.queue.close();
 
-        let threads: Vec<Worker> = self.workers.drain(0..).collect();
-        for t in threads {
-            let _ = t.thread.unwrap().join();
+        for worker in &self.shared.workers {
+            loop {
+                let thread = worker.thread.lock().unwrap().take();
+                match thread {
+                    Some(thread) => {
+                        let _ = thread.join();
+                    }
+                    None => break,
+                }
+            }
         }
     }
 }
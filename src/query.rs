@@ -0,0 +1,283 @@
+use std::fmt;
+
+// The query language combines searches with boolean operators:
+//
+//     query   := or
+//     or      := and ("OR" and)*
+//     and     := not ("AND"? not)*
+//     not     := "NOT" not | near
+//     near    := primary ("NEAR/" distance primary)?
+//     primary := "(" or ")" | word | '"' phrase '"'
+//
+// so `(witch OR witches) AND NOT hamlet` finds the documents that contain "witch" or "witches"
+// but not "hamlet". NOT binds tightest and OR loosest, and two terms with no operator between them
+// must both match. The operators must be written in upper case, so that "and", "or" and "not" can
+// still be searched for as words. A quoted phrase matches documents containing its words
+// consecutively, like `Database::search`. `blood NEAR/5 hand` finds the documents where "blood"
+// and "hand" occur within five words of each other, in either order; both sides of NEAR must be a
+// word or a phrase.
+//
+// Parentheses and NOTs may be nested at most `MAX_DEPTH` deep, since both parsing and evaluating a
+// query recurse once for each level.
+
+/// The deepest that parentheses and NOTs may be nested in a query
+pub const MAX_DEPTH: usize = 64;
+
+/// A parsed boolean query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// Documents containing the word or phrase
+    Term(String),
+    /// Documents matching both queries
+    And(Box<Query>, Box<Query>),
+    /// Documents matching either query
+    Or(Box<Query>, Box<Query>),
+    /// Documents not matching the query
+    Not(Box<Query>),
+    /// Documents where the two words or phrases occur within `distance` terms of each other
+    Near {
+        left: String,
+        right: String,
+        distance: usize,
+    },
+}
+
+/// An error in the syntax of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The byte offset in the query at which the error was found
+    pub position: usize,
+    /// What is wrong with the query at that position
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Near(usize),
+    Word(String),
+    Phrase(String),
+}
+
+impl Query {
+    // Parse a query written in the query language.
+    pub fn parse(query: &str) -> Result<Query, ParseError> {
+        let tokens = lex(query)?;
+        let mut parser = Parser {
+            tokens,
+            next: 0,
+            end: query.len(),
+            depth: 0,
+        };
+        if parser.peek().is_none() {
+            return Err(parser.error("expected a query"));
+        }
+        let parsed = parser.or()?;
+        match parser.peek() {
+            None => Ok(parsed),
+            Some(Token::RightParen) => Err(parser.error("unmatched ')'")),
+            Some(_) => Err(parser.error("expected an operator")),
+        }
+    }
+}
+
+// Split a query into tokens, each paired with the byte offset it starts at.
+fn lex(query: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push((start, Token::LeftParen));
+            }
+            ')' => {
+                chars.next();
+                tokens.push((start, Token::RightParen));
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => phrase.push(c),
+                        None => {
+                            return Err(ParseError {
+                                position: start,
+                                message: "unterminated '\"'".to_string(),
+                            })
+                        }
+                    }
+                }
+                tokens.push((start, Token::Phrase(phrase)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                let token = match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match word.strip_prefix("NEAR/") {
+                        Some(distance) => match distance.parse() {
+                            Ok(distance) => Token::Near(distance),
+                            Err(_) => {
+                                return Err(ParseError {
+                                    position: start,
+                                    message: "expected a distance after 'NEAR/'".to_string(),
+                                })
+                            }
+                        },
+                        None => Token::Word(word),
+                    },
+                };
+                tokens.push((start, token));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent parser with one function per rule of the grammar.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    /// The index of the next token to be consumed
+    next: usize,
+    /// The length of the query, used as the position of errors at the end of the query
+    end: usize,
+    /// How many parentheses and NOTs enclose the next token
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    // An error at the position of the next token
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            position: self
+                .tokens
+                .get(self.next)
+                .map_or(self.end, |(position, _)| *position),
+            message: message.to_string(),
+        }
+    }
+
+    // Parse the part of the query inside a parenthesis or NOT, which has just been consumed, with
+    // `parse`. Fails at the parenthesis or NOT if it nests the query more than `MAX_DEPTH` deep.
+    fn nested<F>(&mut self, parse: F) -> Result<Query, ParseError>
+    where
+        F: FnOnce(&mut Self) -> Result<Query, ParseError>,
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError {
+                position: self.tokens[self.next - 1].0,
+                message: "query is nested too deeply".to_string(),
+            });
+        }
+        self.depth += 1;
+        let query = parse(self);
+        self.depth -= 1;
+        query
+    }
+
+    fn or(&mut self) -> Result<Query, ParseError> {
+        let mut query = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, ParseError> {
+        let mut query = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.next += 1,
+                Some(Token::Not | Token::LeftParen | Token::Word(_) | Token::Phrase(_)) => {}
+                _ => return Ok(query),
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query, ParseError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next += 1;
+            let query = self.nested(Self::not)?;
+            return Ok(Query::Not(Box::new(query)));
+        }
+        self.near()
+    }
+
+    fn near(&mut self) -> Result<Query, ParseError> {
+        let start = self.next;
+        let left = self.primary()?;
+        let distance = match self.peek() {
+            Some(Token::Near(distance)) => *distance,
+            _ => return Ok(left),
+        };
+        let left = match left {
+            Query::Term(left) if self.next == start + 1 => left,
+            _ => {
+                self.next = start;
+                return Err(self.error("expected a word or phrase before NEAR"));
+            }
+        };
+        self.next += 1;
+        let right = match self.peek().cloned() {
+            Some(Token::Word(right)) | Some(Token::Phrase(right)) => right,
+            _ => return Err(self.error("expected a word or phrase after NEAR")),
+        };
+        self.next += 1;
+        Ok(Query::Near {
+            left,
+            right,
+            distance,
+        })
+    }
+
+    fn primary(&mut self) -> Result<Query, ParseError> {
+        match self.peek().cloned() {
+            Some(Token::LeftParen) => {
+                self.next += 1;
+                let query = self.nested(Self::or)?;
+                if self.peek() != Some(&Token::RightParen) {
+                    return Err(self.error("expected ')'"));
+                }
+                self.next += 1;
+                Ok(query)
+            }
+            Some(Token::Word(word)) | Some(Token::Phrase(word)) => {
+                self.next += 1;
+                Ok(Query::Term(word))
+            }
+            _ => Err(self.error("expected a word, phrase or '('")),
+        }
+    }
+}
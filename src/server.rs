@@ -1,48 +1,366 @@
-use crate::database::Database;
+use crate::database::{Database, DatabaseConfig, DatabaseError, TextRange};
+use crate::dictionary::TermPattern;
 use crate::message::*;
-use crate::pool::ThreadPool;
-use std::io::Write;
+use crate::pool::{panic_message, PoolConfig, ThreadPool};
+use crate::query::Query;
+use std::io::{self, ErrorKind, Write};
 use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
 };
 use std::thread;
+use std::time::{Duration, Instant};
 
-/// The number of workers in the server's thread pool
-const WORKERS: usize = 16;
+/// The most documents returned in one page of search results, whatever limit the client asks for
+const MAX_PAGE_SIZE: usize = 10_000;
+/// The most bytes of a document sent in one chunk of a streamed retrieve
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How often an open connection checks whether it has gone idle or the server has stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 // TODO:
-// Implement the `process_message` function. This function should take a `ServerState`, a `Request`,
-// and a `TcpStream`. It should process the request and write the response to the stream.
+// Implement the `process_message` function. This function should take a `ServerState` and a
+// `Request`. It should process the request and return the response to send back to the client.
 // Processing the request should simply require calling the appropriate function on the database
-// and then creating the appropriate response and turning it into bytes which are sent to along
-// the stream by calling the `write_all` method.
-fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStream) {
+// and then creating the appropriate response.
+//
+// `capabilities` are the optional features negotiated with the client, which decide whether a
+// search that matches nothing can be answered with spelling suggestions.
+fn process_message(state: &ServerState, request: Request, capabilities: u32) -> Response {
     match request {
-        Request::Publish { doc } => {
-            let result = state.database.publish(doc);
-            // check for error?
-            let response = Response::PublishSuccess(result);
-            stream.write_all(&mut response.to_bytes()).unwrap();
+        Request::Publish { doc } => match state.database.publish(doc) {
+            Ok(id) => Response::PublishSuccess(id),
+            Err(err) => database_error(err),
+        },
+        Request::Retrieve { id } => match state.database.retrieve(id) {
+            Ok(doc) => Response::RetrieveSuccess(doc),
+            Err(err) => database_error(err),
+        },
+        Request::Search { word } => {
+            let ids = state.database.search(&word);
+            if ids.is_empty() && capabilities & CAP_SUGGESTIONS != 0 {
+                let suggestions = state.database.suggestions(&word);
+                if !suggestions.is_empty() {
+                    return Response::NoMatches(suggestions);
+                }
+            }
+            Response::SearchSuccess(ids)
+        }
+        Request::Frequency { word } => Response::FrequencySuccess(state.database.frequency(&word)),
+        Request::Metadata { id } => match state.database.metadata(id) {
+            Ok(metadata) => Response::MetadataSuccess(metadata),
+            Err(err) => database_error(err),
+        },
+        Request::Timeline { word } => Response::TimelineSuccess(state.database.timeline(&word)),
+        Request::Delete { id } => match state.database.delete(id) {
+            Ok(()) => Response::DeleteSuccess,
+            Err(err) => database_error(err),
+        },
+        Request::RankedSearch { word, limit } => {
+            Response::RankedSearchSuccess(state.database.ranked_search(&word, limit))
+        }
+        Request::Concordance {
+            word,
+            context,
+            snippets,
+        } => Response::ConcordanceSuccess(state.database.concordance(&word, context, snippets)),
+        Request::SearchPage {
+            word,
+            limit,
+            cursor,
+        } => Response::SearchPageSuccess(state.database.search_page(
+            &word,
+            limit.min(MAX_PAGE_SIZE),
+            cursor,
+        )),
+        // Connections stream this request with `stream_document` instead, but it can still be
+        // answered here, with the whole range as a single chunk
+        Request::RetrieveStream { id, range } => match state.database.document(id) {
+            Ok(text) => {
+                let (start, end) = range.resolve(&text);
+                Response::Chunk {
+                    data: text.as_bytes()[start..end].to_vec(),
+                    last: true,
+                }
+            }
+            Err(err) => database_error(err),
+        },
+        Request::Stats => Response::StatsSuccess(ServerStats {
+            workers: state.pool.size(),
+            queue_depth: state.pool.queue_depth(),
+            panics: state.pool.panic_counts().iter().sum(),
+        }),
+        Request::PatternSearch {
+            pattern: TermPattern::Fuzzy { max_distance, .. },
+        } if max_distance > state.limits.max_distance => Response::Error(ErrorResponse::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "fuzzy search allows {} edits, but at most {} are allowed",
+                max_distance, state.limits.max_distance
+            ),
+        )),
+        Request::PatternSearch { pattern } => match state.database.pattern_search(&pattern) {
+            Ok(ids) => Response::SearchSuccess(ids),
+            Err(err) => database_error(err),
+        },
+        Request::Query { query } => match Query::parse(&query) {
+            Ok(query) => Response::SearchSuccess(state.database.query(&query)),
+            Err(err) => Response::Error(ErrorResponse::new(
+                ErrorCode::InvalidQuery,
+                format!("invalid query: {}", err),
+            )),
+        },
+    }
+}
+
+// Send part of a document as a series of `Response::Chunk`s of at most `CHUNK_SIZE` bytes, the
+// last of which is marked as such. The document's text is shared with the blob store rather than
+// copied out of it, so only one chunk is copied at a time. Other responses on the connection may
+// be sent in between the chunks, since each frame is written separately.
+fn stream_document<F>(
+    state: &ServerState,
+    id: usize,
+    range: TextRange,
+    mut send: F,
+) -> io::Result<()>
+where
+    F: FnMut(Response) -> io::Result<()>,
+{
+    let text = match state.database.document(id) {
+        Ok(text) => text,
+        Err(err) => return send(database_error(err)),
+    };
+    let (start, end) = range.resolve(&text);
+    let mut chunks = text.as_bytes()[start..end].chunks(CHUNK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        return send(Response::Chunk {
+            data: Vec::new(),
+            last: true,
+        });
+    }
+    while let Some(chunk) = chunks.next() {
+        send(Response::Chunk {
+            data: chunk.to_vec(),
+            last: chunks.peek().is_none(),
+        })?;
+    }
+    Ok(())
+}
+
+// Turn a failed database operation into an error response. Storage failures are unexpected, so
+// they are logged as well.
+fn database_error(err: DatabaseError) -> Response {
+    if let DatabaseError::Io(_) = err {
+        println!("Database operation failed: {}", err);
+    }
+    Response::Error(ErrorResponse::from(&err))
+}
+
+// Serve the requests sent over a single connection until the client disconnects, sends a frame
+// that can't be read, or leaves the connection idle for longer than the idle timeout. This thread
+// only reads whole frames, within the size limit; each frame is then decoded and processed on the
+// thread pool, so requests pipelined on one connection can run concurrently, and each response is
+// written as soon as it is ready. A frame that can't be decoded is answered with an error response
+// and the connection carries on, since the framing is still intact. The write half of the stream
+// is shared behind a mutex so that response frames are never interleaved.
+//
+// If the thread pool's queue is full, the request is answered straight away with a retryable
+// `Overloaded` error instead of waiting for room, so a burst of requests can't queue up without
+// limit. A request whose processing panics is answered with an `Internal` error.
+//
+// Before any requests are read, the client's handshake is answered with the server's own. If the
+// client doesn't speak the protocol, or has no protocol version in common with the server, the
+// connection is closed.
+//
+// The connection is polled for the start of the next frame with a short timeout, so that the
+// thread notices when the server is stopped or the connection has gone idle. A connection that is
+// still waiting on responses is never considered idle. Writes time out too, so a client that stops
+// reading its responses can't hold up the workers writing them.
+fn handle_connection(state: Arc<ServerState>, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(state.idle_timeout))?;
+    stream.set_write_timeout(Some(state.write_timeout))?;
+    let hello = match Hello::from_bytes(&mut stream) {
+        Ok(hello) => hello,
+        Err(err) => {
+            println!("Closing connection after failed handshake: {}", err);
+            return Ok(());
         }
-        Request::Retrieve { id } => {
-            let result = state.database.retrieve(id);
-            match result {
-                Some(str) => {
-                    let response = Response::RetrieveSuccess(str);
-                    stream.write_all(&mut response.to_bytes()).unwrap();
+    };
+    let ours = Hello::default();
+    stream.write_all(&ours.to_bytes())?;
+    let capabilities = match ours.negotiate(&hello) {
+        Some((_, capabilities)) => capabilities,
+        None => {
+            println!(
+                "Closing connection from client speaking protocol versions {}-{}",
+                hello.min_version, hello.max_version
+            );
+            return Ok(());
+        }
+    };
+
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let mut last_active = Instant::now();
+    loop {
+        stream.set_read_timeout(Some(POLL_INTERVAL.min(state.idle_timeout)))?;
+        match stream.peek(&mut [0; 1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if state.is_stopped.load(Ordering::SeqCst) {
+                    return Ok(());
                 }
-                None => {
-                    let response = Response::Failure;
-                    stream.write_all(&mut response.to_bytes()).unwrap();
+                if in_flight.load(Ordering::SeqCst) > 0 {
+                    last_active = Instant::now();
+                } else if last_active.elapsed() >= state.idle_timeout {
+                    return Ok(());
                 }
+                continue;
             }
+            Err(err) => return Err(err),
         }
-        Request::Search { word } => {
-            let results = state.database.search(&word);
-            let response = Response::SearchSuccess(results);
-            stream.write_all(&mut response.to_bytes()).unwrap();
+
+        // A frame has started arriving, so give the rest of it until the idle timeout to arrive
+        stream.set_read_timeout(Some(state.idle_timeout))?;
+        let (id, length) = match read_frame_header(&mut stream) {
+            Ok(header) => header,
+            Err(DecodeError::Io(err)) => return Err(err),
+            Err(_) => return Ok(()),
+        };
+        if let Err(err) = check_frame_length(length, state.limits.max_frame) {
+            // The rest of the frame is never read, so the connection can't be used any further
+            println!("Closing connection after oversized frame: {}", err);
+            let response = Response::Error(ErrorResponse::from(&err));
+            write_response(&writer, id, response)?;
+            return Ok(());
+        }
+        let payload = match read_frame_payload(&mut stream, length) {
+            Ok(payload) => payload,
+            Err(DecodeError::Io(err)) => return Err(err),
+            Err(_) => return Ok(()),
+        };
+        last_active = Instant::now();
+
+        let job = {
+            let state_copy = Arc::clone(&state);
+            let writer = Arc::clone(&writer);
+            let in_flight = InFlight::new(Arc::clone(&in_flight));
+            move || {
+                let _in_flight = in_flight;
+                let send = |response: Response| write_response(&writer, id, response);
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| {
+                        match Request::decode_payload(&payload, &state_copy.limits) {
+                            Ok(Request::RetrieveStream { id, range }) => {
+                                stream_document(&state_copy, id, range, send)
+                            }
+                            Ok(request) => {
+                                send(process_message(&state_copy, request, capabilities))
+                            }
+                            Err(err) => {
+                                println!("Received invalid request: {}", err);
+                                send(Response::Error(ErrorResponse::from(&err)))
+                            }
+                        }
+                    }));
+                let result = match result {
+                    Ok(result) => result,
+                    // The client is still owed a response. The panic is then passed on to the pool,
+                    // which counts it and reports it to its panic hook.
+                    Err(payload) => {
+                        let _ = send(Response::Error(ErrorResponse::new(
+                            ErrorCode::Internal,
+                            "the server failed to process the request",
+                        )));
+                        panic::resume_unwind(payload);
+                    }
+                };
+                if let Err(err) = result {
+                    println!("Failed to send response: {}", err);
+                }
+            }
+        };
+        // A job that is handed back is dropped straight away, which ends its request
+        if state.pool.try_execute(job).is_err() {
+            let response = Response::Error(ErrorResponse::new(
+                ErrorCode::Overloaded,
+                "server is overloaded, retry later",
+            ));
+            write_response(&writer, id, response)?;
+        }
+    }
+}
+
+/// A request on a connection that has yet to be answered, which is counted in the connection's
+/// requests in flight until it is dropped, even if processing it panics
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Write `response` to the connection as a frame with the given id. A response too large to fit in
+// a frame is replaced with an error, so that the client still gets an answer.
+fn write_response(writer: &Mutex<TcpStream>, id: u64, response: Response) -> io::Result<()> {
+    let frame = match response.to_frame(id) {
+        Ok(frame) => frame,
+        Err(err) => {
+            println!("Failed to frame response: {}", err);
+            let response = Response::Error(ErrorResponse::new(
+                ErrorCode::TooLarge,
+                "response is too large to send",
+            ));
+            response.to_frame(id)?
+        }
+    };
+    writer.lock().unwrap().write_all(&frame)
+}
+
+/// Options controlling how a `Server` is set up
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Options passed through to the server's `Database`
+    pub database: DatabaseConfig,
+    /// The directory to persist the database in. If `None`, the database is kept in memory only.
+    pub data_dir: Option<PathBuf>,
+    /// How often to compact deleted documents and write a snapshot of a persistent database
+    pub snapshot_interval: Duration,
+    /// How long a connection may go without sending a request before the server closes it
+    pub idle_timeout: Duration,
+    /// How long writing a response may block before the connection is given up on
+    pub write_timeout: Duration,
+    /// The most connections served at once; any more are closed as soon as they are accepted
+    pub max_connections: usize,
+    /// The largest frames, documents and words that clients may send
+    pub limits: Limits,
+    /// The number of workers processing requests, and how many requests may wait for them
+    pub pool: PoolConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            database: DatabaseConfig::default(),
+            data_dir: None,
+            snapshot_interval: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(10),
+            max_connections: 256,
+            limits: Limits::default(),
+            pool: PoolConfig::default(),
         }
     }
 }
@@ -55,13 +373,61 @@ struct ServerState {
     pool: ThreadPool,
     /// A flag that indicates whether the server has been stopped
     is_stopped: AtomicBool,
+    /// How often to compact and snapshot the database
+    snapshot_interval: Duration,
+    /// How long an idle connection is kept open
+    idle_timeout: Duration,
+    /// How long a response may take to write
+    write_timeout: Duration,
+    /// The most connections served at once
+    max_connections: usize,
+    /// The number of connections currently being served
+    connections: AtomicUsize,
+    /// The largest requests that are accepted
+    limits: Limits,
 }
 impl ServerState {
-    fn new() -> Self {
-        Self {
-            database: Database::new(),
-            pool: ThreadPool::new(WORKERS),
+    fn new(config: ServerConfig) -> Result<Self, DatabaseError> {
+        let database = match &config.data_dir {
+            Some(dir) => Database::open(config.database, dir)?,
+            None => Database::with_config(config.database),
+        };
+        let mut pool = config.pool;
+        if pool.panic_hook.is_none() {
+            pool.panic_hook = Some(Arc::new(|worker, payload| {
+                println!(
+                    "Request panicked on worker {}: {}",
+                    worker,
+                    panic_message(payload)
+                )
+            }));
+        }
+        let mut limits = config.limits;
+        limits.max_frame = limits.max_frame.min(u32::MAX as usize);
+        Ok(Self {
+            database,
+            pool: ThreadPool::with_config(pool),
             is_stopped: AtomicBool::new(false),
+            snapshot_interval: config.snapshot_interval,
+            idle_timeout: config.idle_timeout,
+            write_timeout: config.write_timeout,
+            max_connections: config.max_connections,
+            connections: AtomicUsize::new(0),
+            limits,
+        })
+    }
+
+    // Compact away deleted documents, then write a snapshot of the database if it is persistent
+    // and has changed, logging any failure.
+    fn maintain(&self) {
+        let compacted = self.database.compact();
+        if compacted > 0 {
+            println!("Compacted {} deleted documents.", compacted);
+        }
+        match self.database.snapshot() {
+            Ok(true) => println!("Wrote database snapshot."),
+            Ok(false) => {}
+            Err(err) => println!("Failed to write database snapshot: {}", err),
         }
     }
 }
@@ -69,42 +435,57 @@ impl ServerState {
 pub struct Server {
     state: Arc<ServerState>,
 }
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Server {
-    // Create a new server by using the `ServerState::new` function
+    // Create a new in-memory server with the default configuration
     pub fn new() -> Self {
-        Server {
-            state: Arc::new(ServerState::new()),
-        }
+        Self::with_config(ServerConfig::default()).expect("in-memory server cannot fail to start")
+    }
+
+    // Create a new server by using the `ServerState::new` function. This fails if the server has
+    // a data directory and the database stored in it cannot be recovered.
+    pub fn with_config(config: ServerConfig) -> Result<Self, DatabaseError> {
+        Ok(Server {
+            state: Arc::new(ServerState::new(config)?),
+        })
     }
 
     // TODO:
     // Spawn a thread that listens for incoming connections on the given port. When a connection is
-    // established, add a task to the thread pool that deserializes the request, and processes it
-    // using the `process_message` function.
+    // established, spawn a thread for it that reads requests with `handle_connection` until the
+    // connection is closed. Connections get their own threads rather than pool workers, since
+    // they spend most of their time blocked waiting for the client.
     //
     // To listen for incoming connections, you can use the `std::net::TcpListener::bind` function.
     // To listen on the local address, you can call `TcpListener::bind(("127.0.0.1", port))`. The
     // resulting TcpListener can be used to accept incoming connections by calling the `accept`
     // method in a loop. This method blocks until a new connection is established, and then returns
-    // a new TcpStream and the address of the remote peer. You should move this stream into the
-    // task that you send to the thread pool.
+    // a new TcpStream and the address of the remote peer.
     //
     // While looping to accept connections, you should also check the `is_stopped` flag in the
     // `ServerState` to see if the server has been stopped. If it has, you should break out of the
     // loop and return.
+    //
+    // Once `max_connections` connections are being served, further connections are closed as soon
+    // as they are accepted, so that a flood of clients can't spawn threads without limit.
     fn listen(&self, port: u16) {
         let state = Arc::clone(&self.state);
         let _response = thread::spawn(move || {
-            let listener_result = TcpListener::bind(("127.0.0.1", port));
-            if listener_result.is_err() {
-                println!(
-                    "Listener returning due to error binding to port {}: {}",
-                    port,
-                    listener_result.unwrap_err()
-                );
-                return;
-            }
-            let listener = listener_result.unwrap();
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    println!(
+                        "Listener returning due to error binding to port {}: {}",
+                        port, err
+                    );
+                    return;
+                }
+            };
             loop {
                 if state.is_stopped.load(Ordering::SeqCst) {
                     println!("Listen returning, server stopped.");
@@ -112,12 +493,20 @@ impl Server {
                 }
                 let connection = listener.accept();
                 match connection {
-                    Ok((mut stream, _)) => {
-                        let request = Request::from_bytes(&mut stream).unwrap();
+                    Ok((stream, addr)) => {
+                        if state.connections.fetch_add(1, Ordering::SeqCst) >= state.max_connections
+                        {
+                            state.connections.fetch_sub(1, Ordering::SeqCst);
+                            println!("Refusing connection from {}, too many connections.", addr);
+                            continue;
+                        }
                         let copy = Arc::clone(&state);
-                        state
-                            .pool
-                            .execute(move || process_message(copy, request, stream))
+                        thread::spawn(move || {
+                            if let Err(err) = handle_connection(Arc::clone(&copy), stream) {
+                                println!("Connection closed due to error: {}", err);
+                            }
+                            copy.connections.fetch_sub(1, Ordering::SeqCst);
+                        });
                     }
                     Err(_err) => {
                         println!("Listen returning, new connection error.");
@@ -147,9 +536,19 @@ impl Server {
             }
         }
 
-        // TODO: Call the listen function and then loop (doing nothing) until the server has been stopped
+        // Call the listen function and then loop until the server has been stopped, periodically
+        // compacting the database and writing a snapshot of it. This is done once more on the way
+        // out.
         self.listen(port);
-        while !self.state.is_stopped.load(Ordering::SeqCst) {}
+        let mut last_snapshot = Instant::now();
+        while !self.state.is_stopped.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+            if last_snapshot.elapsed() >= self.state.snapshot_interval {
+                self.state.maintain();
+                last_snapshot = Instant::now();
+            }
+        }
+        self.state.maintain();
     }
     pub fn stop(&self) {
         self.state.is_stopped.store(true, Ordering::SeqCst);
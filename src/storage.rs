@@ -0,0 +1,307 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// The storage struct keeps a database durable across restarts using two files in its data
+// directory:
+//
+// - `wal`: a write-ahead log. Every change is appended (and synced to disk) here before it is
+//   applied in memory, so a crash can never lose a change that was acknowledged to a client.
+// - `snapshot`: a compacted copy of the whole database. Writing a snapshot lets the log be
+//   truncated, so that recovery doesn't have to replay every change ever made.
+//
+// Both files are a sequence of records. Each record is framed as a 4-byte length and a 4-byte
+// checksum, followed by a payload of an 8-byte log sequence number (LSN), a 1-byte tag and the
+// record data. The snapshot additionally starts with a magic number and the LSN of the last log
+// record it includes, so log records that are already part of the snapshot are skipped on
+// recovery, even if the server crashed between writing the snapshot and truncating the log.
+
+const LOG_FILE: &str = "wal";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"NGRMSNP1";
+
+/// A change to the database that is recorded in the log or a snapshot
+#[derive(Debug, PartialEq)]
+pub(crate) enum Record<'a> {
+    /// A document was published
+    Publish(Cow<'a, str>),
+    /// The document with the given id was deleted
+    Delete(usize),
+    /// Used in snapshots in place of a deleted document, so that later documents keep their ids
+    Tombstone,
+}
+
+/// The write-ahead log and snapshot files of a database
+pub(crate) struct Storage {
+    /// The directory containing the log and snapshot files
+    dir: PathBuf,
+    /// The log, opened for appending
+    log: File,
+    /// The LSN that will be given to the next appended record
+    next_lsn: u64,
+    /// The LSN of the last record that is included in the snapshot
+    snapshot_lsn: u64,
+    /// Whether a failed append could not be rolled back, leaving the end of the log in an unknown
+    /// state. Nothing more is appended to the log once it has failed.
+    failed: bool,
+}
+
+impl Storage {
+    // Open the storage in `dir`, creating the directory if it does not exist. Returns the storage
+    // along with every record needed to rebuild the database: first the records of the latest
+    // snapshot, then the log records written after it. A partially written record at the end of
+    // the log (from a crash in the middle of an append) is discarded and truncated away. A corrupt
+    // record that is followed by more of the log can't be the result of a crash, and discarding it
+    // would lose the acknowledged records after it, so the storage refuses to open instead.
+    pub(crate) fn open(dir: &Path) -> io::Result<(Storage, Vec<Record<'static>>)> {
+        fs::create_dir_all(dir)?;
+
+        let mut records = Vec::new();
+        let mut snapshot_lsn = 0;
+        match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut magic = [0; 8];
+                reader.read_exact(&mut magic)?;
+                if &magic != SNAPSHOT_MAGIC {
+                    return Err(invalid_data("snapshot has an unknown format"));
+                }
+                let mut lsn = [0; 8];
+                reader.read_exact(&mut lsn)?;
+                snapshot_lsn = u64::from_be_bytes(lsn);
+                while let Some((_, record)) = read_record(&mut reader)? {
+                    records.push(record);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut next_lsn = snapshot_lsn + 1;
+        let mut valid_length = 0;
+        {
+            let mut reader = BufReader::new(&mut log);
+            loop {
+                match read_record(&mut reader) {
+                    Ok(Some((lsn, record))) => {
+                        valid_length = reader.stream_position()?;
+                        if lsn > snapshot_lsn {
+                            records.push(record);
+                        }
+                        next_lsn = next_lsn.max(lsn + 1);
+                    }
+                    Ok(None) => break,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        let log_length = log.metadata()?.len();
+        if log_length != valid_length && record_end(&mut log, valid_length)? < log_length {
+            return Err(invalid_data(&format!(
+                "write-ahead log has a corrupt record at byte {} followed by more data",
+                valid_length
+            )));
+        }
+        if log_length != valid_length {
+            println!("Discarding torn record at the end of the write-ahead log");
+            log.set_len(valid_length)?;
+            log.sync_all()?;
+        }
+
+        let storage = Storage {
+            dir: dir.to_path_buf(),
+            log,
+            next_lsn,
+            snapshot_lsn,
+            failed: false,
+        };
+        Ok((storage, records))
+    }
+
+    // Durably append a record to the log. The record is on disk once this returns successfully.
+    // If the append fails, the log is truncated back to where it was, so that a partly written or
+    // unsynced record can't be replayed after a restart. If even that fails, the storage is marked
+    // as failed and refuses every later append.
+    pub(crate) fn append(&mut self, record: &Record) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other(
+                "write-ahead log is unusable after an earlier failure",
+            ));
+        }
+        let bytes = encode_record(self.next_lsn, record)?;
+        let length = self.log.metadata()?.len();
+        let result = self
+            .log
+            .write_all(&bytes)
+            .and_then(|()| self.log.sync_data());
+        if let Err(err) = result {
+            if self
+                .log
+                .set_len(length)
+                .and_then(|()| self.log.sync_all())
+                .is_err()
+            {
+                self.failed = true;
+            }
+            return Err(err);
+        }
+        self.next_lsn += 1;
+        Ok(())
+    }
+
+    // Whether any records have been appended to the log since the last snapshot
+    pub(crate) fn has_changes(&self) -> bool {
+        self.next_lsn - 1 > self.snapshot_lsn
+    }
+
+    // Replace the snapshot with one containing `records`, which must describe the whole database
+    // as of the last appended record, and then truncate the log. The snapshot is written to a
+    // temporary file which is atomically renamed into place, so a crash leaves either the old or
+    // the new snapshot intact.
+    pub(crate) fn write_snapshot<'a, I>(&mut self, records: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Record<'a>>,
+    {
+        let last_lsn = self.next_lsn - 1;
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(SNAPSHOT_MAGIC)?;
+            writer.write_all(&last_lsn.to_be_bytes())?;
+            for record in records {
+                writer.write_all(&encode_record(0, &record)?)?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.snapshot_lsn = last_lsn;
+
+        self.log.set_len(0)?;
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.sync_all()?;
+        Ok(())
+    }
+}
+
+// Encode a record with the given LSN, including its length and checksum framing. Fails if the
+// record is too long for its 4-byte length.
+fn encode_record(lsn: u64, record: &Record) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    payload.extend(lsn.to_be_bytes().iter());
+    match record {
+        Record::Publish(doc) => {
+            payload.push(0);
+            payload.extend(doc.as_bytes());
+        }
+        Record::Delete(id) => {
+            payload.push(1);
+            payload.extend((*id as u64).to_be_bytes().iter());
+        }
+        Record::Tombstone => payload.push(2),
+    }
+
+    let length = u32::try_from(payload.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "record is {} bytes long, which is too long to store",
+                payload.len()
+            ),
+        )
+    })?;
+    let mut bytes = Vec::with_capacity(payload.len() + 8);
+    bytes.extend(length.to_be_bytes().iter());
+    bytes.extend(checksum(&payload).to_be_bytes().iter());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+// The offset just past the record that starts at `start` in `file`, according to its length. A
+// record whose header is cut off ends at the end of the file.
+fn record_end(file: &mut File, start: u64) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut length = [0; 4];
+    match file.read_exact(&mut length) {
+        Ok(()) => Ok(start + 8 + u32::from_be_bytes(length) as u64),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(u64::MAX),
+        Err(err) => Err(err),
+    }
+}
+
+// Read the next record and its LSN from `reader`. Returns `Ok(None)` at a clean end of input, an
+// `UnexpectedEof` error if the input ends in the middle of a record, and an `InvalidData` error if
+// the record is corrupt.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<(u64, Record<'static>)>> {
+    let mut header = [0; 8];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let expected = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if length < 9 {
+        return Err(invalid_data("record is too short"));
+    }
+
+    let mut payload = Vec::new();
+    reader.take(length as u64).read_to_end(&mut payload)?;
+    if payload.len() != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if checksum(&payload) != expected {
+        return Err(invalid_data("record checksum mismatch"));
+    }
+
+    let lsn = u64::from_be_bytes(payload[0..8].try_into().unwrap());
+    let record = match payload[8] {
+        0 => {
+            let doc = String::from_utf8(payload[9..].to_vec())
+                .map_err(|_| invalid_data("document is not valid UTF-8"))?;
+            Record::Publish(Cow::Owned(doc))
+        }
+        1 => {
+            let id: [u8; 8] = payload[9..]
+                .try_into()
+                .map_err(|_| invalid_data("delete record has the wrong length"))?;
+            Record::Delete(u64::from_be_bytes(id) as usize)
+        }
+        2 => Record::Tombstone,
+        _ => return Err(invalid_data("unknown record type")),
+    };
+    Ok(Some((lsn, record)))
+}
+
+// A 32-bit FNV-1a hash, used to detect torn or corrupted records.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
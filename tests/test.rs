@@ -1,3 +1,11 @@
+// The baseline tests are kept as they were written, lints and all
+#![allow(
+    unused_variables,
+    clippy::assertions_on_constants,
+    clippy::clone_on_copy,
+    clippy::empty_loop,
+    clippy::unnecessary_cast
+)]
 use quickcheck::quickcheck;
 const THREADS: usize = 16;
 
@@ -96,6 +104,103 @@ mod test_multimap {
         }
         quickcheck(passes_stress_test as fn(Vec<(i32, usize, bool)>));
     }
+    #[test]
+    fn test_grows_when_full() {
+        let map = ConcurrentMultiMap::<UnCloneable, usize>::new(1);
+        for i in 0..10_000 {
+            map.set(UnCloneable(i), i as usize);
+            map.set(UnCloneable(i), i as usize + 1);
+        }
+        assert_eq!(map.len(), 20_000);
+        assert!(map.bucket_count() >= 20_000 / 8);
+        for i in 0..10_000 {
+            let mut values = map.get(&UnCloneable(i));
+            values.sort();
+            assert_eq!(values, vec![i as usize, i as usize + 1]);
+        }
+    }
+    #[test]
+    fn test_grows_concurrently() {
+        use std::sync::Arc;
+        let map = Arc::new(ConcurrentMultiMap::<UnCloneable, usize>::new(1));
+        let threads = (0..8)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                std::thread::spawn(move || {
+                    for i in 0..5_000 {
+                        let k = t * 5_000 + i;
+                        map.set(UnCloneable(k), k as usize);
+                        assert_eq!(map.get(&UnCloneable(k)), vec![k as usize]);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(map.len(), 40_000);
+        for k in 0..40_000 {
+            assert_eq!(map.get(&UnCloneable(k)), vec![k as usize]);
+        }
+    }
+    #[test]
+    fn test_remove() {
+        fn remove(k: i32, values: Vec<usize>, removed: usize) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(4);
+            for v in values.iter() {
+                map.set(UnCloneable(k), *v);
+            }
+            let had = values.contains(&removed);
+            assert_eq!(map.remove(&UnCloneable(k), &removed), had);
+            assert!(!map.get(&UnCloneable(k)).contains(&removed));
+            assert!(!map.remove(&UnCloneable(k), &removed));
+
+            let mut rest = map.remove_all(&UnCloneable(k));
+            rest.sort();
+            let mut expected: Vec<usize> = values.into_iter().filter(|v| *v != removed).collect();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(rest, expected);
+            assert!(map.is_empty());
+        }
+        quickcheck(remove as fn(i32, Vec<usize>, usize));
+    }
+    #[test]
+    fn test_retain() {
+        let map = ConcurrentMultiMap::<UnCloneable, usize>::new(2);
+        for k in 0..1000 {
+            map.set(UnCloneable(k), k as usize);
+            map.set(UnCloneable(k), k as usize * 2);
+        }
+        map.retain(|_, v| v % 2 == 1);
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&UnCloneable(3)), vec![3]);
+        assert_eq!(map.get(&UnCloneable(4)), Vec::<usize>::new());
+    }
+    #[test]
+    fn test_update_is_atomic() {
+        use std::sync::Arc;
+        let map = Arc::new(ConcurrentMultiMap::<String, usize>::new(1));
+        let threads = (0..8)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        map.set(format!("filler-{}-{}", t, i), i);
+                        map.update("counter".to_string(), |values| {
+                            let count = values.pop().unwrap_or(0);
+                            values.push(count + 1);
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(map.get("counter"), vec![4000]);
+        assert_eq!(map.len(), 4001);
+
+        let removed = map.update("counter".to_string(), |values| values.drain(..).count());
+        assert_eq!(removed, 1);
+        assert_eq!(map.len(), 4000);
+    }
 }
 
 // ============================ POOL ============================
@@ -140,18 +245,292 @@ mod test_pool {
         drop(pool);
         assert_eq!(*counter.lock().unwrap(), 8);
     }
+
+    #[test]
+    fn test_bounded_queue() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 1,
+            queue_capacity: 2,
+            ..Default::default()
+        });
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started_rx.recv().unwrap();
+
+        // The worker is busy, so two jobs fill the queue and the next is handed back
+        let counter = Arc::new(Mutex::new(0));
+        let job = {
+            let counter = Arc::clone(&counter);
+            move || *counter.lock().unwrap() += 1
+        };
+        assert!(pool.try_execute(job.clone()).is_ok());
+        assert!(pool.try_execute(job.clone()).is_ok());
+        assert!(pool.try_execute(job.clone()).is_err());
+        let start = std::time::Instant::now();
+        let timeout = std::time::Duration::from_millis(100);
+        assert!(pool.execute_timeout(job.clone(), timeout).is_err());
+        assert!(start.elapsed() >= timeout);
+
+        // Once the worker is free, waiting jobs run and make room
+        release_tx.send(()).unwrap();
+        assert!(pool
+            .execute_timeout(job, std::time::Duration::from_secs(10))
+            .is_ok());
+        drop(pool);
+        assert_eq!(*counter.lock().unwrap(), 3);
+    }
+    #[test]
+    fn test_panics_are_isolated() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let hook: PanicHook = {
+            let reported = Arc::clone(&reported);
+            Arc::new(move |worker, payload| {
+                let message = panic_message(payload).to_string();
+                reported.lock().unwrap().push((worker, message.clone()));
+                if message == "hook panics too" {
+                    panic!("{}", message);
+                }
+            })
+        };
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 2,
+            max_workers: 2,
+            panic_hook: Some(hook),
+            ..Default::default()
+        });
+        for i in 0..10 {
+            pool.execute(move || panic!("job {} failed", i));
+        }
+        pool.execute(|| panic!("hook panics too"));
+
+        // Both workers are still around to run jobs that block until they all have started
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+        for _ in 0..2 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+
+        assert_eq!(pool.panic_counts().len(), 2);
+        assert_eq!(pool.panic_counts().iter().sum::<usize>(), 11);
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 11);
+        assert!(reported.iter().all(|(worker, _)| *worker < 2));
+        assert!(reported
+            .iter()
+            .any(|(_, message)| message == "job 9 failed"));
+    }
+
+    #[test]
+    fn test_elastic_size() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 3,
+            keep_alive: std::time::Duration::from_millis(100),
+            ..Default::default()
+        });
+        assert_eq!(pool.size(), 1);
+
+        // Jobs that block until released back the queue up, so the pool grows to its maximum
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        for _ in 0..5 {
+            let release_rx = Arc::clone(&release_rx);
+            pool.execute(move || {
+                release_rx.lock().unwrap().recv().unwrap();
+            });
+        }
+        assert_eq!(pool.size(), 3);
+        while pool.queue_depth() > 2 {
+            std::thread::yield_now();
+        }
+        for _ in 0..5 {
+            release_tx.send(()).unwrap();
+        }
+
+        // Once they have finished, the extra workers retire after the keep-alive
+        let start = std::time::Instant::now();
+        while pool.size() > 1 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(pool.queue_depth(), 0);
+        assert_eq!(pool.panic_counts().len(), 3);
+        assert_eq!(pool.spawn(|| 42).join().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_retiring_worker_leaves_no_job_behind() {
+        // With a keep-alive this short, jobs keep arriving just as the only worker retires
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 0,
+            max_workers: 1,
+            keep_alive: std::time::Duration::from_micros(200),
+            ..Default::default()
+        });
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for i in 0..2000 {
+            std::thread::sleep(std::time::Duration::from_micros(i % 400));
+            let done_tx = done_tx.clone();
+            pool.execute(move || done_tx.send(i).unwrap());
+            let done = done_rx.recv_timeout(std::time::Duration::from_secs(5));
+            assert_eq!(done, Ok(i));
+        }
+    }
+
+    #[test]
+    fn test_work_stealing() {
+        let pool = ThreadPool::new(2);
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        pool.execute(move || release_rx.recv().unwrap());
+        for i in 0..100 {
+            let done_tx = done_tx.clone();
+            pool.execute(move || done_tx.send(i).unwrap());
+        }
+
+        // Jobs queued behind the blocked one, including any moved into the blocked worker's own
+        // deque, are all run by the other worker
+        let mut done: Vec<i32> = (0..100)
+            .map(|_| {
+                done_rx
+                    .recv_timeout(std::time::Duration::from_secs(10))
+                    .unwrap()
+            })
+            .collect();
+        done.sort();
+        assert_eq!(done, (0..100).collect::<Vec<_>>());
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_spawn() {
+        let pool = ThreadPool::new(4);
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(100);
+        let words: Vec<String> = text.split_whitespace().map(String::from).collect();
+        let handles: Vec<JobHandle<usize>> = words
+            .chunks(50)
+            .map(|chunk| {
+                let chunk = chunk.to_vec();
+                pool.spawn(move || chunk.iter().filter(|word| *word == "the").count())
+            })
+            .collect();
+        let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(total, 200);
+
+        let panicked = pool.spawn(|| -> usize { panic!("job failed") });
+        let payload = panicked.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let handle = pool.spawn(move || release_rx.recv().unwrap());
+        assert!(!handle.is_finished());
+        let handle = handle.try_join().err().unwrap();
+        release_tx.send(()).unwrap();
+        while !handle.is_finished() {
+            std::thread::yield_now();
+        }
+        assert!(matches!(handle.try_join(), Ok(Ok(()))));
+    }
 }
 
 // ============================ SERIALIZE ============================
 mod test_serialize {
     use super::*;
+    use ngram::database::{
+        Frequency, Hit, Metadata, ScoredDocument, SearchPage, Snippet, TextRange, TimelinePoint,
+    };
+    use ngram::dictionary::TermPattern;
     use ngram::message::*;
     #[test]
     fn test_round_trip_request_5() {
         fn round_trip_request(s: String, n: usize) {
             let pub_request = Request::Publish { doc: s.clone() };
-            let search_request = Request::Search { word: s };
+            let search_request = Request::Search { word: s.clone() };
             let retrieve_request = Request::Retrieve { id: n };
+            let frequency_request = Request::Frequency { word: s.clone() };
+            let delete_request = Request::Delete { id: n };
+            let query_request = Request::Query { query: s.clone() };
+            assert_eq!(
+                Request::from_bytes(&Request::Stats.to_bytes()[..]).unwrap(),
+                Request::Stats
+            );
+            for range in [
+                TextRange::All,
+                TextRange::Bytes {
+                    start: n / 2,
+                    end: n,
+                },
+                TextRange::Lines { first: n, last: 0 },
+            ] {
+                let stream_request = Request::RetrieveStream { id: n, range };
+                assert_eq!(
+                    Request::from_bytes(&stream_request.to_bytes()[..]).unwrap(),
+                    stream_request
+                );
+            }
+            for cursor in [None, Some(n / 2)] {
+                let page_request = Request::SearchPage {
+                    word: s.clone(),
+                    limit: n,
+                    cursor,
+                };
+                assert_eq!(
+                    Request::from_bytes(&page_request.to_bytes()[..]).unwrap(),
+                    page_request
+                );
+            }
+            for pattern in [
+                TermPattern::Fuzzy {
+                    term: s.clone(),
+                    max_distance: n,
+                },
+                TermPattern::Prefix(s.clone()),
+                TermPattern::Wildcard(s.clone()),
+                TermPattern::Regex(s.clone()),
+            ] {
+                let pattern_request = Request::PatternSearch { pattern };
+                assert_eq!(
+                    Request::from_bytes(&pattern_request.to_bytes()[..]).unwrap(),
+                    pattern_request
+                );
+            }
+            let concordance_request = Request::Concordance {
+                word: s.clone(),
+                context: n,
+                snippets: n / 2,
+            };
+            assert_eq!(
+                Request::from_bytes(&concordance_request.to_bytes()[..]).unwrap(),
+                concordance_request
+            );
+            assert_eq!(
+                Request::from_bytes(&query_request.to_bytes()[..]).unwrap(),
+                query_request
+            );
+            let ranked_request = Request::RankedSearch {
+                word: s.clone(),
+                limit: n,
+            };
+            assert_eq!(
+                Request::from_bytes(&ranked_request.to_bytes()[..]).unwrap(),
+                ranked_request
+            );
+            assert_eq!(
+                Request::from_bytes(&delete_request.to_bytes()[..]).unwrap(),
+                delete_request
+            );
+            assert_eq!(
+                Request::from_bytes(&frequency_request.to_bytes()[..]).unwrap(),
+                frequency_request
+            );
             assert_eq!(
                 Request::from_bytes(&pub_request.to_bytes()[..]).unwrap(),
                 pub_request
@@ -174,6 +553,101 @@ mod test_serialize {
             let pub_response = Response::PublishSuccess(n);
             let search_response = Response::SearchSuccess(vec![n]);
             let retrieve_response = Response::RetrieveSuccess(s.clone());
+            let ranked_response = Response::RankedSearchSuccess(vec![
+                ScoredDocument { id: n, score: 2.5 },
+                ScoredDocument {
+                    id: n / 2,
+                    score: -0.0,
+                },
+            ]);
+            assert_eq!(
+                Response::from_bytes(&ranked_response.to_bytes()[..]).unwrap(),
+                ranked_response
+            );
+            for response in [
+                Response::DeleteSuccess,
+                Response::StatsSuccess(ServerStats {
+                    workers: n,
+                    queue_depth: n / 2,
+                    panics: 0,
+                }),
+                Response::Chunk {
+                    data: s.clone().into_bytes(),
+                    last: n.is_multiple_of(2),
+                },
+                Response::Chunk {
+                    data: vec![],
+                    last: true,
+                },
+                Response::NoMatches(vec![s.clone(), String::new()]),
+                Response::SearchPageSuccess(SearchPage {
+                    ids: vec![n, n / 2],
+                    total: n,
+                    next: Some(n / 2),
+                }),
+                Response::SearchPageSuccess(SearchPage {
+                    ids: vec![],
+                    total: 0,
+                    next: None,
+                }),
+                Response::ConcordanceSuccess(vec![
+                    Hit {
+                        id: n,
+                        count: n / 2,
+                        snippets: vec![Snippet {
+                            start: n,
+                            end: n / 3,
+                            before: s.clone(),
+                            matched: "blood".to_string(),
+                            after: String::new(),
+                        }],
+                    },
+                    Hit {
+                        id: 0,
+                        count: 0,
+                        snippets: vec![],
+                    },
+                ]),
+                Response::Error(ErrorResponse::new(ErrorCode::Deleted, s.clone())),
+                Response::Error(ErrorResponse {
+                    code: ErrorCode::from_u16(n as u16),
+                    message: s.clone(),
+                    retryable: true,
+                }),
+            ] {
+                assert_eq!(
+                    Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                    response
+                );
+            }
+            let frequency_response = Response::FrequencySuccess(vec![Frequency {
+                id: n,
+                count: n / 2,
+                relative: n as f64 / 7.0,
+            }]);
+            assert_eq!(
+                Response::from_bytes(&frequency_response.to_bytes()[..]).unwrap(),
+                frequency_response
+            );
+            let metadata_response = Response::MetadataSuccess(Metadata {
+                title: Some(s.clone()),
+                author: None,
+                year: Some(n as i32),
+            });
+            assert_eq!(
+                Response::from_bytes(&metadata_response.to_bytes()[..]).unwrap(),
+                metadata_response
+            );
+            let timeline_response = Response::TimelineSuccess(vec![TimelinePoint {
+                year: -(n as i32),
+                count: n,
+                word_count: n / 3,
+                relative: 0.25,
+            }]);
+            assert_eq!(
+                Response::from_bytes(&timeline_response.to_bytes()[..]).unwrap(),
+                timeline_response
+            );
             assert_eq!(
                 Response::from_bytes(&pub_response.to_bytes()[..]).unwrap(),
                 pub_response
@@ -189,6 +663,827 @@ mod test_serialize {
         }
         quickcheck(round_trip_response as fn(String, usize));
     }
+
+    #[test]
+    fn test_round_trip_frame() {
+        fn round_trip_frame(s: String, id: u64) {
+            let request = Request::Search { word: s.clone() };
+            let mut bytes = request.to_frame(id).unwrap();
+            bytes.extend(
+                Request::Delete { id: 3 }
+                    .to_frame(id.wrapping_add(1))
+                    .unwrap(),
+            );
+            let mut reader = &bytes[..];
+            assert_eq!(Request::from_frame(&mut reader), Some((id, request)));
+            assert_eq!(
+                Request::from_frame(&mut reader),
+                Some((id.wrapping_add(1), Request::Delete { id: 3 }))
+            );
+            assert_eq!(Request::from_frame(&mut reader), None);
+
+            let response = Response::RetrieveSuccess(s);
+            assert_eq!(
+                Response::from_frame(&response.to_frame(id).unwrap()[..]).ok(),
+                Some((id, response))
+            );
+        }
+        quickcheck(round_trip_frame as fn(String, u64));
+    }
+
+    #[test]
+    fn test_handshake_negotiation() {
+        let hello = Hello {
+            min_version: 2,
+            max_version: 4,
+            capabilities: 0b1011,
+        };
+        assert_eq!(Hello::from_bytes(&hello.to_bytes()[..]).unwrap(), hello);
+        assert!(matches!(
+            Hello::from_bytes(&b"GET / HTTP/1.1"[..]),
+            Err(DecodeError::BadMagic)
+        ));
+
+        let other = Hello {
+            min_version: 3,
+            max_version: 5,
+            capabilities: 0b0110,
+        };
+        assert_eq!(hello.negotiate(&other), Some((4, 0b0010)));
+        assert_eq!(other.negotiate(&hello), Some((4, 0b0010)));
+        let old = Hello {
+            min_version: 1,
+            max_version: 1,
+            capabilities: 0,
+        };
+        assert_eq!(hello.negotiate(&old), None);
+
+        // Integers have the same width on every platform
+        let bytes = Request::Retrieve { id: 1 }.to_bytes();
+        assert_eq!(bytes, [2, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed() {
+        let limits = Limits {
+            max_frame: 64,
+            max_document: 32,
+            max_word: 8,
+            max_distance: 1,
+        };
+        let decode = |bytes: &[u8]| Request::decode_payload(bytes, &limits).unwrap_err();
+
+        assert!(matches!(decode(&[42]), DecodeError::UnknownTag(42)));
+        assert!(matches!(decode(&[]), DecodeError::Truncated));
+        assert!(matches!(decode(&[2, 0, 0]), DecodeError::Truncated));
+
+        let mut bytes = vec![1];
+        bytes.extend(usize::MAX.to_be_bytes());
+        assert!(matches!(
+            decode(&bytes),
+            DecodeError::TooLarge { field: "word", .. }
+        ));
+        let long_word = Request::Search {
+            word: "ninechars".to_string(),
+        };
+        assert!(matches!(
+            decode(&long_word.to_bytes()),
+            DecodeError::TooLarge { field: "word", .. }
+        ));
+
+        let mut bytes = vec![0];
+        bytes.extend(2usize.to_be_bytes());
+        bytes.extend([0xff, 0xfe]);
+        assert!(matches!(decode(&bytes), DecodeError::InvalidUtf8));
+
+        let mut bytes = Request::Retrieve { id: 1 }.to_bytes();
+        bytes.push(0);
+        assert!(matches!(decode(&bytes), DecodeError::TrailingBytes(1)));
+
+        let mut bytes = vec![10, 4];
+        bytes.extend(1usize.to_be_bytes());
+        bytes.push(b'a');
+        assert!(matches!(decode(&bytes), DecodeError::Malformed));
+
+        let frame = Request::Publish {
+            doc: "x".repeat(100),
+        }
+        .to_frame(5)
+        .unwrap();
+        assert!(matches!(
+            read_frame(&mut &frame[..], limits.max_frame),
+            Err(DecodeError::TooLarge { field: "frame", .. })
+        ));
+
+        fn never_panics(bytes: Vec<u8>) {
+            let _ = Request::decode_payload(&bytes, &Limits::default());
+            let _ = Response::from_bytes(&bytes[..]);
+        }
+        quickcheck(never_panics as fn(Vec<u8>));
+    }
+}
+
+// ============================ DATABASE ============================
+mod test_database {
+    use ngram::database::*;
+
+    #[test]
+    fn test_phrase_search() {
+        for max_ngram in 1..=3 {
+            let db = Database::with_config(DatabaseConfig {
+                max_ngram,
+                ..Default::default()
+            });
+            let a = db
+                .publish("all haile to thee thane of cawdor".to_string())
+                .unwrap();
+            let b = db
+                .publish("thane of glamis and cawdor of thane".to_string())
+                .unwrap();
+            assert_eq!(db.search("thane of cawdor"), vec![a]);
+            assert_eq!(db.search("to thee thane of cawdor"), vec![a]);
+            assert_eq!(db.search("of thane"), vec![b]);
+            assert_eq!(db.search("cawdor thane"), Vec::<usize>::new());
+            let mut both = db.search("thane of");
+            both.sort();
+            assert_eq!(both, vec![a, b]);
+        }
+    }
+
+    #[test]
+    fn test_frequency() {
+        for max_ngram in 1..=2 {
+            let db = Database::with_config(DatabaseConfig {
+                max_ngram,
+                ..Default::default()
+            });
+            let a = db
+                .publish("the cat and the dog and the bird".to_string())
+                .unwrap();
+            let b = db.publish("a dog".to_string()).unwrap();
+            assert_eq!(
+                db.frequency("the"),
+                vec![Frequency {
+                    id: a,
+                    count: 3,
+                    relative: 3.0 / 8.0
+                }]
+            );
+            assert_eq!(
+                db.frequency("and the"),
+                vec![Frequency {
+                    id: a,
+                    count: 2,
+                    relative: 2.0 / 8.0
+                }]
+            );
+            let dog = db.frequency("dog");
+            assert_eq!(dog.len(), 2);
+            assert_eq!((dog[1].id, dog[1].count, dog[1].relative), (b, 1, 0.5));
+        }
+    }
+
+    #[test]
+    fn test_metadata_from_header() {
+        let metadata =
+            Metadata::from_header("[The Tragedie of Macbeth by William Shakespeare 1603]\n");
+        assert_eq!(metadata.title.as_deref(), Some("The Tragedie of Macbeth"));
+        assert_eq!(metadata.author.as_deref(), Some("William Shakespeare"));
+        assert_eq!(metadata.year, Some(1603));
+
+        let metadata = Metadata::from_header("[The Parent's Assistant, by Maria Edgeworth]");
+        assert_eq!(metadata.title.as_deref(), Some("The Parent's Assistant"));
+        assert_eq!(metadata.author.as_deref(), Some("Maria Edgeworth"));
+        assert_eq!(metadata.year, None);
+
+        let metadata = Metadata::from_header("[The King James Bible]");
+        assert_eq!(metadata.title.as_deref(), Some("The King James Bible"));
+        assert_eq!(metadata.author, None);
+
+        assert_eq!(Metadata::from_header("no header here"), Metadata::default());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let db = Database::new();
+        let a = db
+            .publish("[A by X 1600]\nwitch witch".to_string())
+            .unwrap();
+        db.publish("[B by Y 1600]\nno match here".to_string())
+            .unwrap();
+        db.publish("[C by Z 1700]\na witch".to_string()).unwrap();
+        db.publish("[Undated]\nwitch".to_string()).unwrap();
+
+        assert_eq!(db.metadata(a).unwrap().year, Some(1600));
+        let timeline = db.timeline("witch");
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(
+            (timeline[0].year, timeline[0].count, timeline[0].word_count),
+            (1600, 2, 5)
+        );
+        assert_eq!(timeline[0].relative, 2.0 / 5.0);
+        assert_eq!(
+            (timeline[1].year, timeline[1].count, timeline[1].word_count),
+            (1700, 1, 2)
+        );
+
+        // The header's words are metadata, not part of the text
+        assert!(db.search("by").is_empty());
+        assert!(db.search("undated").is_empty());
+        assert_eq!(db.frequency("witch")[0].relative, 1.0);
+        assert_eq!(db.highlights(a, "witch").unwrap(), vec![(14, 19), (20, 25)]);
+    }
+
+    #[test]
+    fn test_ranked_search() {
+        let db = Database::new();
+        let a = db
+            .publish("witch witch witch cauldron".to_string())
+            .unwrap();
+        let b = db
+            .publish("a witch and a very long story about many other things".to_string())
+            .unwrap();
+        let c = db.publish("cauldron bubble".to_string()).unwrap();
+
+        let ranked = db.ranked_search("Witch", 10);
+        assert_eq!(
+            ranked.iter().map(|doc| doc.id).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+        let idf = (1.0f64 + (3.0 - 2.0 + 0.5) / (2.0 + 0.5)).ln();
+        let average_length = (4.0 + 11.0 + 2.0) / 3.0;
+        let expected = idf * 3.0 * 2.2 / (3.0 + 1.2 * (0.25 + 0.75 * 4.0 / average_length));
+        assert!((ranked[0].score - expected).abs() < 1e-9);
+
+        let ranked = db.ranked_search("witch cauldron witch", 2);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].id, a);
+        assert!(ranked[0].score > ranked[1].score);
+
+        db.delete(a).unwrap();
+        let ranked = db.ranked_search("witch cauldron", 10);
+        let mut ids: Vec<usize> = ranked.iter().map(|doc| doc.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![b, c]);
+        assert!(db.ranked_search("", 10).is_empty());
+
+        // Without length normalization or term frequency, only rarity matters
+        let db = Database::with_config(DatabaseConfig {
+            k1: 0.0,
+            b: 0.0,
+            ..Default::default()
+        });
+        db.publish("rare common common common".to_string()).unwrap();
+        db.publish("common".to_string()).unwrap();
+        let ranked = db.ranked_search("common", 10);
+        assert_eq!(ranked[0].score, ranked[1].score);
+        assert_eq!((ranked[0].id, ranked[1].id), (0, 1));
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ngram-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_recovers_from_log_and_snapshot() {
+        let dir = temp_dir("recovery");
+        let (a, b) = {
+            let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            let a = db.publish("first document".to_string()).unwrap();
+            let b = db.publish("second document".to_string()).unwrap();
+            (a, b)
+        };
+
+        // Recover from the log alone, then snapshot and keep publishing
+        let c = {
+            let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            assert_eq!(db.retrieve(a).unwrap(), "first document");
+            assert_eq!(db.retrieve(b).unwrap(), "second document");
+            assert!(db.snapshot().unwrap());
+            assert!(!db.snapshot().unwrap());
+            db.publish("third document".to_string()).unwrap()
+        };
+        assert_eq!(c, 2);
+
+        // Simulate a crash in the middle of an append
+        use std::io::Write;
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("wal"))
+            .unwrap();
+        log.write_all(&[0, 0, 1, 0, 1, 2]).unwrap();
+        drop(log);
+
+        let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(db.retrieve(c).unwrap(), "third document");
+        let mut ids = db.search("document");
+        ids.sort();
+        assert_eq!(ids, vec![a, b, c]);
+        assert_eq!(db.publish("fourth".to_string()).unwrap(), 3);
+        drop(db);
+
+        let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(db.retrieve(3).unwrap(), "fourth");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_log() {
+        let dir = temp_dir("corrupt");
+        {
+            let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            db.publish("first document".to_string()).unwrap();
+            db.publish("second document".to_string()).unwrap();
+        }
+        let path = dir.join("wal");
+        let log = std::fs::read(&path).unwrap();
+
+        // A corrupt record at the end of the log is a torn append and is discarded
+        let mut torn = log.clone();
+        *torn.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &torn).unwrap();
+        let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(db.retrieve(0).unwrap(), "first document");
+        assert!(db.retrieve(1).is_err());
+        drop(db);
+
+        // One followed by more records can't be, so the log is refused rather than cut short
+        let mut corrupt = log;
+        corrupt[20] ^= 0xff;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(Database::open(DatabaseConfig::default(), &dir).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), corrupt);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_positions() {
+        let config = DatabaseConfig {
+            max_ngram: 2,
+            ..Default::default()
+        };
+        let db = Database::with_config(config);
+        let text = "Out, damned spot! Out, I say! Out, damned spot, out!";
+        let id = db.publish(text.to_string()).unwrap();
+
+        let highlights = db.highlights(id, "damned spot").unwrap();
+        assert_eq!(highlights, vec![(5, 16), (35, 46)]);
+        assert_eq!(&text[highlights[1].0..highlights[1].1], "damned spot");
+        assert_eq!(db.highlights(id, "spot out damned").unwrap(), vec![]);
+        assert_eq!(db.highlights(id, "out damned spot").unwrap().len(), 2);
+        assert_eq!(db.frequency("out damned spot")[0].count, 2);
+        assert_eq!(db.frequency("spot out i")[0].count, 1);
+        assert!(db.highlights(id + 1, "out").is_err());
+    }
+
+    #[test]
+    fn test_concordance() {
+        let db = Database::new();
+        db.publish("nothing here".to_string()).unwrap();
+        let text =
+            "Will all great Neptune's ocean wash this blood\nClean from my hand? No, this my hand \
+                    will rather\nThe multitudinous seas incarnadine";
+        let id = db.publish(text.to_string()).unwrap();
+
+        let hits = db.concordance("my hand", 2, 1);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, id);
+        assert_eq!(hits[0].count, 2);
+        assert_eq!(
+            hits[0].snippets,
+            vec![Snippet {
+                start: 58,
+                end: 65,
+                before: "Clean from ".to_string(),
+                matched: "my hand".to_string(),
+                after: "? No, this".to_string(),
+            }]
+        );
+
+        let hits = db.concordance("will", 100, 10);
+        let snippets = &hits[0].snippets;
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].before, "");
+        assert_eq!(snippets[0].matched, "Will");
+        assert_eq!(
+            format!(
+                "{}{}{}",
+                snippets[1].before, snippets[1].matched, snippets[1].after
+            ),
+            text
+        );
+        assert!(db.concordance("macbeth", 5, 5).is_empty());
+    }
+
+    #[test]
+    fn test_search_page() {
+        let db = Database::new();
+        let ids: Vec<usize> = (0..5)
+            .map(|i| db.publish(format!("page {}", i)).unwrap())
+            .collect();
+
+        let first = db.search_page("page", 2, None);
+        assert_eq!(first.ids, ids[0..2]);
+        assert_eq!(first.total, 5);
+        assert_eq!(first.next, Some(ids[1]));
+
+        // Documents published or deleted between pages don't shift the later pages
+        db.delete(ids[0]).unwrap();
+        db.delete(ids[2]).unwrap();
+        let late = db.publish("late page".to_string()).unwrap();
+        let second = db.search_page("page", 2, first.next);
+        assert_eq!(second.ids, vec![ids[3], ids[4]]);
+        assert_eq!(second.total, 4);
+        let third = db.search_page("page", 2, second.next);
+        assert_eq!(third.ids, vec![late]);
+        assert_eq!(third.next, None);
+
+        assert_eq!(db.search_page("page", 0, None).ids, vec![ids[1]]);
+        assert_eq!(db.search_page("missing", 10, None).total, 0);
+    }
+
+    #[test]
+    fn test_text_range() {
+        let text = "one\ntwo\r\nthree\nfour";
+        let slice = |range: TextRange| {
+            let (start, end) = range.resolve(text);
+            &text[start..end]
+        };
+        assert_eq!(slice(TextRange::All), text);
+        assert_eq!(slice(TextRange::Bytes { start: 4, end: 7 }), "two");
+        assert_eq!(slice(TextRange::Bytes { start: 17, end: 99 }), "ur");
+        assert_eq!(slice(TextRange::Bytes { start: 7, end: 4 }), "");
+        assert_eq!(
+            slice(TextRange::Lines { first: 2, last: 3 }),
+            "two\r\nthree\n"
+        );
+        assert_eq!(slice(TextRange::Lines { first: 4, last: 9 }), "four");
+        assert_eq!(slice(TextRange::Lines { first: 5, last: 9 }), "");
+        assert_eq!(slice(TextRange::Lines { first: 0, last: 1 }), "one\n");
+    }
+
+    #[test]
+    fn test_delete_and_compact() {
+        let db = Database::new();
+        let a = db.publish("the quick fox".to_string()).unwrap();
+        let b = db.publish("the lazy dog".to_string()).unwrap();
+        db.delete(a).unwrap();
+
+        assert_eq!(db.search("the"), vec![b]);
+        assert!(db.frequency("quick").is_empty());
+        assert!(matches!(db.retrieve(a), Err(DatabaseError::Deleted(_))));
+        assert!(matches!(db.delete(a), Err(DatabaseError::Deleted(_))));
+        assert!(matches!(db.delete(9), Err(DatabaseError::NotFound(_))));
+
+        assert_eq!(db.compact(), 1);
+        assert_eq!(db.compact(), 0);
+        assert_eq!(db.search("the"), vec![b]);
+        assert!(matches!(db.retrieve(a), Err(DatabaseError::Deleted(_))));
+        assert_eq!(db.retrieve(b).unwrap(), "the lazy dog");
+        assert_eq!(db.publish("a new fox".to_string()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_delete_is_persistent() {
+        let dir = temp_dir("delete");
+        {
+            let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            db.publish("gone".to_string()).unwrap();
+            db.publish("kept".to_string()).unwrap();
+            db.delete(0).unwrap();
+        }
+        {
+            let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            assert!(matches!(db.retrieve(0), Err(DatabaseError::Deleted(_))));
+            assert!(db.snapshot().unwrap());
+        }
+        let db = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert!(matches!(db.retrieve(0), Err(DatabaseError::Deleted(_))));
+        assert!(db.search("gone").is_empty());
+        assert_eq!(db.retrieve(1).unwrap(), "kept");
+        assert_eq!(db.publish("new".to_string()).unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+// ============================ ANALYSIS ============================
+mod test_analysis {
+    use ngram::analysis::*;
+    use ngram::database::*;
+    use std::sync::Arc;
+
+    fn terms(tokenizer: &dyn Tokenizer, text: &str) -> Vec<String> {
+        tokenizer
+            .tokenize(text)
+            .into_iter()
+            .map(|token| token.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_standard_tokenizer() {
+        let tokenizer = StandardTokenizer::default();
+        assert_eq!(
+            terms(&tokenizer, "\"Macbeth,\" quoth MACBETH. Don't--ÉTÉ 1603!"),
+            vec!["macbeth", "quoth", "macbeth", "don't", "été", "1603"]
+        );
+        let tokens = tokenizer.tokenize("  [Emma, by");
+        assert_eq!((tokens[0].start, tokens[0].end), (3, 7));
+        assert_eq!((tokens[1].start, tokens[1].end), (9, 11));
+
+        let tokenizer = StandardTokenizer {
+            numerals: Numerals::Drop,
+            stopwords: ["the".to_string()].into_iter().collect(),
+            stem: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            terms(&tokenizer, "The 3 witches' cauldrons, the glass"),
+            vec!["witche", "cauldron", "glass"]
+        );
+        assert_eq!(stem("stories"), "story");
+        assert_eq!(stem("thus"), "thus");
+
+        let raw = StandardTokenizer {
+            lowercase: false,
+            strip_punctuation: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            terms(&raw, "Macbeth, macbeth."),
+            vec!["Macbeth,", "macbeth."]
+        );
+    }
+
+    #[test]
+    fn test_search_is_analyzed() {
+        let db = Database::new();
+        let a = db.publish("Enter Macbeth.".to_string()).unwrap();
+        let b = db.publish("\"macbeth, the thane\"".to_string()).unwrap();
+        assert_eq!(db.search("MACBETH"), vec![a, b]);
+        assert_eq!(db.search("Macbeth, the"), vec![b]);
+
+        let db = Database::with_config(DatabaseConfig {
+            max_ngram: 2,
+            tokenizer: Arc::new(StandardTokenizer {
+                stopwords: ["of".to_string()].into_iter().collect(),
+                stem: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let a = db.publish("The Thanes of Cawdor".to_string()).unwrap();
+        assert_eq!(db.search("thane cawdor"), vec![a]);
+        assert_eq!(db.search("thanes of cawdor"), vec![a]);
+        assert!(db.search("of").is_empty());
+        assert_eq!(db.frequency("thane")[0].relative, 1.0 / 3.0);
+    }
+}
+
+// ============================ QUERY ============================
+mod test_query {
+    use ngram::database::*;
+    use ngram::query::*;
+
+    fn term(word: &str) -> Box<Query> {
+        Box::new(Query::Term(word.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Query::parse("(witch OR witches) AND NOT hamlet").unwrap(),
+            Query::And(
+                Box::new(Query::Or(term("witch"), term("witches"))),
+                Box::new(Query::Not(term("hamlet")))
+            )
+        );
+        assert_eq!(
+            Query::parse("a b OR \"thane of cawdor\"").unwrap(),
+            Query::Or(
+                Box::new(Query::And(term("a"), term("b"))),
+                term("thane of cawdor")
+            )
+        );
+        assert_eq!(
+            Query::parse("not and or").unwrap(),
+            Query::And(Box::new(Query::And(term("not"), term("and"))), term("or"))
+        );
+
+        let error = |query: &str| Query::parse(query).unwrap_err().position;
+        assert_eq!(error(""), 0);
+        assert_eq!(error("(witch OR"), 9);
+        assert_eq!(error("witch)"), 5);
+        assert_eq!(error("a AND \"b"), 6);
+        assert_eq!(error("a AND OR b"), 6);
+        assert_eq!(error("(a b"), 4);
+    }
+
+    #[test]
+    fn test_nesting() {
+        let nested = |depth: usize| format!("{}witch{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(Query::parse(&nested(MAX_DEPTH)).unwrap(), *term("witch"));
+        let error = Query::parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(error.position, MAX_DEPTH);
+
+        // A query nested far too deeply fails to parse rather than overflowing the stack
+        assert!(Query::parse(&nested(100_000)).is_err());
+        assert!(Query::parse(&format!("{}witch", "NOT ".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let db = Database::new();
+        let a = db.publish("the witch and the thane".to_string()).unwrap();
+        let b = db.publish("three witches".to_string()).unwrap();
+        let c = db.publish("hamlet meets a witch".to_string()).unwrap();
+        let d = db.publish("nothing to see".to_string()).unwrap();
+
+        let query = |q: &str| db.query(&Query::parse(q).unwrap());
+        assert_eq!(query("(witch OR witches) AND NOT hamlet"), vec![a, b]);
+        assert_eq!(query("witch hamlet"), vec![c]);
+        assert_eq!(query("NOT witch"), vec![b, d]);
+        assert_eq!(query("NOT (witch OR witches)"), vec![d]);
+        assert_eq!(query("\"the thane\" OR three"), vec![a, b]);
+        assert_eq!(query("\"thane the\""), Vec::<usize>::new());
+
+        db.delete(d).unwrap();
+        assert_eq!(query("NOT witch"), vec![b]);
+    }
+
+    #[test]
+    fn test_near() {
+        assert_eq!(
+            Query::parse("blood NEAR/5 \"his hand\" OR hamlet").unwrap(),
+            Query::Or(
+                Box::new(Query::Near {
+                    left: "blood".to_string(),
+                    right: "his hand".to_string(),
+                    distance: 5,
+                }),
+                term("hamlet")
+            )
+        );
+        let error = |query: &str| Query::parse(query).unwrap_err().position;
+        assert_eq!(error("blood NEAR/x hand"), 6);
+        assert_eq!(error("(a OR b) NEAR/2 c"), 0);
+        assert_eq!(error("a NEAR/2 NOT b"), 9);
+
+        let db = Database::new();
+        let a = db
+            .publish(
+                "will all great neptune's ocean wash this blood clean from my hand".to_string(),
+            )
+            .unwrap();
+        let b = db.publish("hand me the blood".to_string()).unwrap();
+        db.publish("blood".to_string()).unwrap();
+
+        let query = |q: &str| db.query(&Query::parse(q).unwrap());
+        assert_eq!(query("blood NEAR/4 hand"), vec![a, b]);
+        assert_eq!(query("blood NEAR/3 hand"), vec![b]);
+        assert_eq!(query("hand NEAR/3 blood"), vec![b]);
+        assert_eq!(query("\"this blood\" NEAR/1 clean"), vec![a]);
+        assert_eq!(query("blood NEAR/0 blood"), Vec::<usize>::new());
+        let far = format!("hand NEAR/{} blood", usize::MAX);
+        assert_eq!(query(&far), vec![a, b]);
+    }
+}
+
+mod test_dictionary {
+    use ngram::database::*;
+    use ngram::dictionary::*;
+
+    #[test]
+    fn test_matches() {
+        let dictionary = TermDictionary::new();
+        let terms = [
+            "than", "that", "the", "thou", "thought", "thousand", "what", "whit", "wh",
+        ];
+        dictionary.extend(terms.iter().map(|term| term.to_string()));
+        let matches = |pattern: TermPattern| dictionary.matches(&pattern, 100).unwrap();
+
+        assert_eq!(
+            matches(TermPattern::Prefix("thou".to_string())),
+            vec!["thou", "thought", "thousand"]
+        );
+        assert_eq!(
+            matches(TermPattern::Prefix("x".to_string())),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            matches(TermPattern::Wildcard("wh?t".to_string())),
+            vec!["what", "whit"]
+        );
+        assert_eq!(
+            matches(TermPattern::Wildcard("*ou*".to_string())),
+            vec!["thou", "thought", "thousand"]
+        );
+        assert_eq!(
+            matches(TermPattern::Wildcard("th*t".to_string())),
+            vec!["that", "thought"]
+        );
+        assert_eq!(
+            matches(TermPattern::Regex("th(e|a[nt])".to_string())),
+            vec!["than", "that", "the"]
+        );
+        assert_eq!(
+            dictionary.matches(&TermPattern::Prefix("th".to_string()), 5),
+            Err(PatternError::TooManyTerms(5))
+        );
+        assert!(matches!(
+            dictionary.matches(&TermPattern::Regex("(th".to_string()), 5),
+            Err(PatternError::InvalidRegex(_))
+        ));
+    }
+
+    // The edit distance between two strings, computed directly.
+    fn levenshtein(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, x) in a.chars().enumerate() {
+            let mut next = vec![i + 1];
+            for (j, y) in b.iter().enumerate() {
+                next.push(
+                    (row[j] + usize::from(x != *y))
+                        .min(row[j + 1] + 1)
+                        .min(next[j] + 1),
+                );
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn test_fuzzy() {
+        let dictionary = TermDictionary::new();
+        let terms = [
+            "scena", "scene", "scoena", "shall", "shalt", "unto", "vnto", "into",
+        ];
+        dictionary.extend(terms.iter().map(|term| term.to_string()));
+        assert_eq!(
+            dictionary.fuzzy("scoena", 1),
+            vec![("scena".to_string(), 1), ("scoena".to_string(), 0)]
+        );
+        assert_eq!(
+            dictionary
+                .matches(
+                    &TermPattern::Fuzzy {
+                        term: "vnto".to_string(),
+                        max_distance: 1
+                    },
+                    10
+                )
+                .unwrap(),
+            vec!["into", "unto", "vnto"]
+        );
+
+        fn same_as_brute_force(terms: Vec<String>, term: String, max_distance: u8) {
+            let max_distance = max_distance as usize % 4;
+            let dictionary = TermDictionary::new();
+            dictionary.extend(terms.iter().cloned());
+            let mut expected: Vec<(String, usize)> = terms
+                .into_iter()
+                .map(|candidate| {
+                    let distance = levenshtein(&term, &candidate);
+                    (candidate, distance)
+                })
+                .filter(|(_, distance)| *distance <= max_distance)
+                .collect();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(dictionary.fuzzy(&term, max_distance), expected);
+        }
+        quickcheck::quickcheck(same_as_brute_force as fn(Vec<String>, String, u8));
+    }
+
+    #[test]
+    fn test_pattern_search() {
+        let db = Database::new();
+        let a = db.publish("Thou shalt not".to_string()).unwrap();
+        let b = db.publish("a thousand times".to_string()).unwrap();
+        let c = db
+            .publish("What light through yonder window".to_string())
+            .unwrap();
+
+        let search = |pattern: TermPattern| db.pattern_search(&pattern).unwrap();
+        assert_eq!(search(TermPattern::Prefix("THOU".to_string())), vec![a, b]);
+        assert_eq!(search(TermPattern::Wildcard("wh?t".to_string())), vec![c]);
+        assert_eq!(
+            search(TermPattern::Regex("th(ou|rough)".to_string())),
+            vec![a, c]
+        );
+
+        db.delete(b).unwrap();
+        assert_eq!(search(TermPattern::Prefix("thou".to_string())), vec![a]);
+        db.compact();
+        assert!(matches!(
+            db.pattern_search(&TermPattern::Prefix("thousand".to_string())),
+            Ok(ids) if ids.is_empty()
+        ));
+    }
 }
 
 // ============================ ARGUMENTS ============================
@@ -199,6 +1494,7 @@ mod test_serialize {
 
 mod integration {
     use super::*;
+    use ngram::dictionary::TermPattern;
     use ngram::message::*;
     use ngram::{client, server};
     use std::fs;
@@ -207,7 +1503,14 @@ mod integration {
     use std::time::Duration;
 
     fn start_server(port: u16) -> (Arc<server::Server>, JoinHandle<()>) {
-        let server = Arc::new(server::Server::new());
+        start_server_with_config(port, server::ServerConfig::default())
+    }
+
+    fn start_server_with_config(
+        port: u16,
+        config: server::ServerConfig,
+    ) -> (Arc<server::Server>, JoinHandle<()>) {
+        let server = Arc::new(server::Server::with_config(config).unwrap());
         let handle = thread::spawn({
             let server = Arc::clone(&server);
             move || server.run(port)
@@ -216,6 +1519,17 @@ mod integration {
         (server, handle)
     }
 
+    // Open a connection to the server and complete the handshake, for tests that send frames by
+    // hand.
+    fn connect(port: u16) -> std::net::TcpStream {
+        use std::io::Write;
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&Hello::default().to_bytes()).unwrap();
+        let hello = Hello::from_bytes(&mut stream).unwrap();
+        assert_eq!(hello.max_version, PROTOCOL_VERSION);
+        stream
+    }
+
     #[test]
     fn test_start_stop_server_5() {
         let port = 7880;
@@ -231,7 +1545,7 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let response = client.publish_from_path("data/austen-emma.txt");
-        assert!(matches!(response, Some(Response::PublishSuccess(_))));
+        assert!(matches!(response, Ok(Response::PublishSuccess(_))));
         server.stop();
     }
 
@@ -242,7 +1556,7 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let response = client.search("a");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![])));
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![]));
         server.stop();
     }
 
@@ -255,11 +1569,11 @@ mod integration {
 
         let response = client.publish_from_path("data/austen-emma.txt");
         let id = match response {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let response = client.search("the");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![id])));
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![id]));
         server.stop();
     }
 
@@ -270,16 +1584,16 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let id1 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let id2 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-persuasion.txt"),
         };
 
         let response = client.search("little");
-        if let Some(Response::SearchSuccess(ids)) = response {
+        if let Ok(Response::SearchSuccess(ids)) = response {
             assert_eq!(ids.len(), 2);
             assert!(ids.contains(&id1));
             assert!(ids.contains(&id2));
@@ -296,16 +1610,467 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let _id1 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-persuasion.txt"),
         };
         let id2 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
 
         let response = client.search("ceased");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![id2])));
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![id2]));
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_phrase() {
+        let port = 7890;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let _id1 = match client.publish_from_path("data/shakespeare-hamlet.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/shakespeare-hamlet.txt"),
+        };
+        let id2 = match client.publish_from_path("data/shakespeare-macbeth.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/shakespeare-macbeth.txt"),
+        };
+
+        let response = client.search("Thane of Cawdor");
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![id2]));
+        server.stop();
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let port = 7891;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let responses = client
+            .pipeline(&[
+                Request::Publish {
+                    doc: "first".to_string(),
+                },
+                Request::Publish {
+                    doc: "second".to_string(),
+                },
+                Request::Retrieve { id: 7 },
+            ])
+            .unwrap();
+        let mut ids = Vec::new();
+        for response in &responses[..2] {
+            match response {
+                Response::PublishSuccess(id) => ids.push(*id),
+                _ => panic!("Unexpected response {:?}", response),
+            }
+        }
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+        assert!(matches!(
+            &responses[2],
+            Response::Error(ErrorResponse {
+                code: ErrorCode::NotFound,
+                retryable: false,
+                ..
+            })
+        ));
+
+        let requests: Vec<Request> = (0..100).map(|i| Request::Retrieve { id: i % 2 }).collect();
+        let responses = client.pipeline(&requests).unwrap();
+        for (i, response) in responses.into_iter().enumerate() {
+            assert_eq!(response, client.retrieve(i % 2).unwrap());
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_idle_connection_is_closed() {
+        use std::io::{Read, Write};
+        let port = 7892;
+        let config = server::ServerConfig {
+            idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (server, _handle) = start_server_with_config(port, config);
+
+        let mut stream = connect(port);
+        stream
+            .write_all(
+                &Request::Search {
+                    word: "a".to_string(),
+                }
+                .to_frame(9)
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            Response::from_frame(&mut stream).ok(),
+            Some((9, Response::SearchSuccess(vec![])))
+        );
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        // A client reconnects after its connection has been closed
+        let client = client::Client::new("127.0.0.1", port);
+        assert!(client.search("a").is_ok());
+        thread::sleep(Duration::from_millis(500));
+        assert!(client.search("a").is_ok());
+
+        // A publish on a connection the server has closed is sent once, on a new connection
+        thread::sleep(Duration::from_millis(500));
+        let publish = Request::Publish {
+            doc: "alpha beta".to_string(),
+        };
+        let responses = client.pipeline(&[publish]).unwrap();
+        assert!(matches!(responses[0], Response::PublishSuccess(_)));
+        assert_eq!(
+            client.search("alpha").unwrap(),
+            Response::SearchSuccess(vec![0])
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        use std::io::Write;
+        let port = 7901;
+        let config = server::ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let (server, _handle) = start_server_with_config(port, config);
+
+        // A second connection is closed without a handshake while the first is open
+        let first = connect(port);
+        let mut second = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        second.write_all(&Hello::default().to_bytes()).unwrap();
+        assert!(Hello::from_bytes(&mut second).is_err());
+
+        drop(first);
+        thread::sleep(Duration::from_millis(300));
+        let client = client::Client::new("127.0.0.1", port);
+        assert!(client.search("a").is_ok());
+        server.stop();
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        use std::io::Write;
+        let port = 7893;
+        let config = server::ServerConfig {
+            limits: Limits {
+                max_frame: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (server, _handle) = start_server_with_config(port, config);
+
+        // A frame that can't be decoded gets an error, and the connection stays usable
+        let mut stream = connect(port);
+        let mut frame = 3u32.to_be_bytes().to_vec();
+        frame.extend(7u64.to_be_bytes());
+        frame.extend([0, 0xff, 0xff]);
+        stream.write_all(&frame).unwrap();
+        assert!(matches!(
+            Response::from_frame(&mut stream).ok(),
+            Some((
+                7,
+                Response::Error(ErrorResponse {
+                    code: ErrorCode::InvalidRequest,
+                    ..
+                })
+            ))
+        ));
+        stream
+            .write_all(
+                &Request::Search {
+                    word: "a".to_string(),
+                }
+                .to_frame(8)
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            Response::from_frame(&mut stream).ok(),
+            Some((8, Response::SearchSuccess(vec![])))
+        );
+
+        // An oversized frame gets an error and the connection is closed
+        let mut frame = u32::MAX.to_be_bytes().to_vec();
+        frame.extend(9u64.to_be_bytes());
+        stream.write_all(&frame).unwrap();
+        assert!(matches!(
+            Response::from_frame(&mut stream).ok(),
+            Some((
+                9,
+                Response::Error(ErrorResponse {
+                    code: ErrorCode::TooLarge,
+                    ..
+                })
+            ))
+        ));
+        assert_eq!(Response::from_frame(&mut stream).ok(), None);
+
+        // Garbage doesn't stop the server from accepting connections
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        drop(stream);
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.search("a").unwrap(), Response::SearchSuccess(vec![]));
+        server.stop();
+    }
+
+    #[test]
+    fn test_client_errors() {
+        let port = 7894;
+        let client = client::Client::new("127.0.0.1", port);
+        assert!(matches!(
+            client.search("a"),
+            Err(client::ClientError::Connect(_))
+        ));
+
+        let (server, _handle) = start_server(port);
+        match client.retrieve(3) {
+            Err(client::ClientError::Server(err)) => {
+                assert_eq!(err.code, ErrorCode::NotFound);
+                assert!(!err.retryable);
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert!(matches!(
+            client.publish_from_path("data/no-such-file.txt"),
+            Err(client::ClientError::Io(_))
+        ));
+        let id = match client.publish_from_path("data/blake-poems.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt"),
+        };
+        assert_eq!(client.delete(id).unwrap(), Response::DeleteSuccess);
+        match client.metadata(id) {
+            Err(client::ClientError::Server(err)) => assert_eq!(err.code, ErrorCode::Deleted),
+            response => panic!("Unexpected response {:?}", response),
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_handshake() {
+        use std::io::{Read, Write};
+        let port = 7895;
+        let (server, _handle) = start_server(port);
+
+        // A client that only speaks a newer protocol is refused after the handshake
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let newer = Hello {
+            min_version: PROTOCOL_VERSION + 1,
+            max_version: PROTOCOL_VERSION + 3,
+            capabilities: u32::MAX,
+        };
+        stream.write_all(&newer.to_bytes()).unwrap();
+        assert_eq!(Hello::from_bytes(&mut stream).unwrap(), Hello::default());
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        // A version 1 client, which sends a frame straight away, is refused
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut frame = vec![1];
+        frame.extend(6usize.to_be_bytes());
+        frame.extend(b"witch!");
+        stream.write_all(&frame).unwrap();
+        // The server closes the connection with part of the frame unread, so it may be reset
+        assert!(matches!(stream.read(&mut [0; 1]), Ok(0) | Err(_)));
+
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.capabilities().unwrap(), CAPABILITIES);
+        server.stop();
+
+        // The client refuses a server with no version in common
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 7896)).unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Hello::from_bytes(&mut stream).unwrap();
+            let older = Hello {
+                min_version: 1,
+                max_version: 1,
+                capabilities: 0,
+            };
+            stream.write_all(&older.to_bytes()).unwrap();
+        });
+        let client = client::Client::new("127.0.0.1", 7896);
+        assert!(matches!(
+            client.search("a"),
+            Err(client::ClientError::Incompatible(_))
+        ));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_query() {
+        let port = 7897;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let hamlet = match client.publish_from_path("data/shakespeare-hamlet.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/shakespeare-hamlet.txt"),
+        };
+        let macbeth = match client.publish_from_path("data/shakespeare-macbeth.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/shakespeare-macbeth.txt"),
+        };
+        let response = client.query("(witch OR witches) AND NOT hamlet");
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![macbeth]));
+        let response = client.query("\"Thane of Cawdor\" OR Ophelia");
+        let mut ids = match response.unwrap() {
+            Response::SearchSuccess(ids) => ids,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        ids.sort();
+        assert_eq!(ids, vec![hamlet, macbeth]);
+
+        match client.query("witch AND (hamlet") {
+            Err(client::ClientError::Server(err)) => {
+                assert_eq!(err.code, ErrorCode::InvalidQuery);
+                assert!(err.message.contains("position 17"));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let port = 7898;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let bible = match client.publish_from_path("data/bible-kjv.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/bible-kjv.txt"),
+        };
+        let response = client.search("vnto");
+        match response.unwrap() {
+            Response::NoMatches(suggestions) => assert_eq!(suggestions[0], "unto"),
+            response => panic!("Unexpected response {:?}", response),
+        }
+        let response = client.search("thou shallt");
+        assert_eq!(
+            response.unwrap(),
+            Response::NoMatches(vec!["thou shalt".to_string()])
+        );
+        let response = client.pattern_search(TermPattern::Fuzzy {
+            term: "Shallt".to_string(),
+            max_distance: 1,
+        });
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![bible]));
+        let response = client.pattern_search(TermPattern::Fuzzy {
+            term: "shallt".to_string(),
+            max_distance: usize::MAX,
+        });
+        match response {
+            Err(client::ClientError::Server(err)) => {
+                assert_eq!(err.code, ErrorCode::InvalidRequest)
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        let response = client.search("qqqqqqqq");
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![]));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_stream() {
+        use ngram::database::TextRange;
+        use std::io::Read;
+        let port = 7899;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/bible-kjv.txt") {
+            Ok(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/bible-kjv.txt"),
+        };
+        let doc = std::fs::read_to_string("data/bible-kjv.txt").unwrap();
+        let mut streamed = String::new();
+        let mut reader = client.retrieve_stream(id, TextRange::All).unwrap();
+        reader.read_to_string(&mut streamed).unwrap();
+        drop(reader);
+        assert_eq!(streamed, doc);
+
+        let mut lines = String::new();
+        client
+            .retrieve_stream(id, TextRange::Lines { first: 2, last: 3 })
+            .unwrap()
+            .read_to_string(&mut lines)
+            .unwrap();
+        let expected: Vec<&str> = doc.split_inclusive('\n').skip(1).take(2).collect();
+        assert_eq!(lines, expected.concat());
+
+        // Dropping a stream part way through leaves the client usable
+        let mut partial = [0; 10];
+        client
+            .retrieve_stream(id, TextRange::All)
+            .unwrap()
+            .read_exact(&mut partial)
+            .unwrap();
+        assert_eq!(&partial[..], &doc.as_bytes()[..10]);
+        let response = client.retrieve_stream(id + 1, TextRange::All);
+        assert!(matches!(response, Err(client::ClientError::Server(_))));
+        let response = client.search("unto");
+        assert_eq!(response.unwrap(), Response::SearchSuccess(vec![id]));
+        server.stop();
+    }
+
+    #[test]
+    fn test_overloaded() {
+        let port = 7900;
+        let config = server::ServerConfig {
+            pool: ngram::pool::PoolConfig {
+                min_workers: 1,
+                max_workers: 1,
+                queue_capacity: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (server, _handle) = start_server_with_config(port, config);
+
+        // One worker and room for one more request can't keep up with a burst of large publishes
+        let doc = std::fs::read_to_string("data/bible-kjv.txt").unwrap();
+        let requests: Vec<Request> = (0..8)
+            .map(|_| Request::Publish { doc: doc.clone() })
+            .collect();
+        let client = client::Client::new("127.0.0.1", port);
+        let responses = client.pipeline(&requests).unwrap();
+        assert!(matches!(responses[0], Response::PublishSuccess(_)));
+        let overloaded = responses
+            .iter()
+            .filter(|response| match response {
+                Response::Error(err) => {
+                    assert_eq!(err.code, ErrorCode::Overloaded);
+                    assert!(err.retryable);
+                    true
+                }
+                _ => false,
+            })
+            .count();
+        assert!(overloaded > 0);
+
+        // The server keeps serving once the burst has passed
+        let response = client.search("unto");
+        assert!(matches!(response.unwrap(), Response::SearchSuccess(ids) if !ids.is_empty()));
+        match client.stats().unwrap() {
+            Response::StatsSuccess(stats) => {
+                assert_eq!(stats.workers, 1);
+                assert_eq!(stats.panics, 0);
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
         server.stop();
     }
 
@@ -316,12 +2081,12 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let id = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Ok(Response::PublishSuccess(id)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
         let response = client.retrieve(id);
-        assert_eq!(response, Some(Response::RetrieveSuccess(doc)));
+        assert_eq!(response.unwrap(), Response::RetrieveSuccess(doc));
         server.stop();
     }
 
@@ -364,7 +2129,7 @@ mod integration {
                         match path {
                             Some(path) => {
                                 println!("Thread {}: processing {}", i, path);
-                                client.publish_from_path(path);
+                                client.publish_from_path(path).unwrap();
                             }
                             None => return,
                         }
@@ -385,7 +2150,10 @@ mod integration {
         let client = client::Client::new("127.0.0.1", port);
         for word in words.iter() {
             let response = client.search(word);
-            assert!(matches!(response, Some(Response::SearchSuccess(_))));
+            assert!(matches!(
+                response,
+                Ok(Response::SearchSuccess(_) | Response::NoMatches(_))
+            ));
         }
         // println!("Sequential search took {:?}", _now.elapsed);
 
@@ -402,7 +2170,10 @@ mod integration {
                         match word {
                             Some(word) => {
                                 let response = client.search(&word);
-                                assert!(matches!(response, Some(Response::SearchSuccess(_))));
+                                assert!(matches!(
+                                    response,
+                                    Ok(Response::SearchSuccess(_) | Response::NoMatches(_))
+                                ));
                                 //println!("Found {} in {:?}", word, indices);
                             }
                             None => return,